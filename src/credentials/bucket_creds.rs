@@ -0,0 +1,143 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use pyo3::prelude::*;
+use tracing::info;
+
+use crate::utils::python::{CallbackError, CallbackPool};
+use crate::utils::ttl_cache::TtlCache;
+
+/// Looks up the API key of buckets that have none in the cos map by calling the Python
+/// `bucket_creds_fetcher` with the bucket name. Keys are remembered for `ttl`, and buckets
+/// the callback has no credentials for for `negative_ttl`, so the callback runs once per
+/// bucket and period rather than once per request. Failed lookups are not cached.
+#[derive(Debug)]
+pub struct BucketCredsFetcher {
+    callback: Arc<PyObject>,
+    pool: Arc<CallbackPool>,
+    cache: TtlCache<String, CallbackError>,
+}
+
+impl BucketCredsFetcher {
+    pub fn new(
+        callback: PyObject,
+        pool: Arc<CallbackPool>,
+        ttl: Duration,
+        negative_ttl: Duration,
+    ) -> Self {
+        BucketCredsFetcher {
+            callback: Arc::new(callback),
+            pool,
+            cache: TtlCache::new(ttl, negative_ttl),
+        }
+    }
+
+    /// The API key for `bucket`, or `None` when the callback has no credentials for it.
+    pub async fn get(&self, bucket: &str) -> Result<Option<String>, CallbackError> {
+        self.cache
+            .get_or_lookup(bucket, || async {
+                info!("Fetching credentials for bucket {}", bucket);
+                self.pool
+                    .call::<_, Option<String>>(&self.callback, (bucket.to_string(),))
+                    .await
+            })
+            .await
+    }
+
+    pub fn invalidate(&self, bucket: &str) {
+        self.cache.invalidate(bucket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyList;
+    use std::time::Instant;
+
+    /// A fetcher with an API key for `known` only, and the list of buckets it was called for.
+    fn fetcher(ttl: Duration, negative_ttl: Duration) -> (BucketCredsFetcher, Py<PyList>) {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let calls = PyList::empty(py);
+            let callback = py
+                .eval(
+                    c"lambda calls: lambda bucket: calls.append(bucket) or ('api-key' if bucket == 'known' else None)",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .call1((&calls,))
+                .unwrap();
+            let pool = Arc::new(CallbackPool::new(2, 10, Duration::from_secs(5)));
+            (
+                BucketCredsFetcher::new(callback.unbind(), pool, ttl, negative_ttl),
+                calls.unbind(),
+            )
+        })
+    }
+
+    fn call_count(calls: &Py<PyList>) -> usize {
+        Python::with_gil(|py| calls.bind(py).len())
+    }
+
+    #[tokio::test]
+    async fn test_known_and_unknown_buckets_are_cached() {
+        let (fetcher, calls) = fetcher(Duration::from_secs(300), Duration::from_secs(30));
+        for _ in 0..3 {
+            assert_eq!(
+                fetcher.get("known").await.unwrap().as_deref(),
+                Some("api-key")
+            );
+            assert_eq!(fetcher.get("unknown").await.unwrap(), None);
+        }
+        assert_eq!(call_count(&calls), 2);
+
+        fetcher.invalidate("known");
+        fetcher.get("known").await.unwrap();
+        assert_eq!(call_count(&calls), 3);
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let (fetcher, _) = fetcher(Duration::from_secs(300), Duration::from_secs(30));
+        let now = Instant::now();
+        fetcher
+            .cache
+            .remember("known", &Some("api-key".to_string()), now);
+        fetcher.cache.remember("unknown", &None, now);
+
+        let later = now + Duration::from_secs(31);
+        assert_eq!(
+            fetcher.cache.cached("known", later),
+            Some(Some("api-key".to_string()))
+        );
+        assert_eq!(fetcher.cache.cached("unknown", later), None);
+        assert_eq!(
+            fetcher
+                .cache
+                .cached("known", now + Duration::from_secs(301)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_zero_ttl_disables_caching() {
+        let (fetcher, _) = fetcher(Duration::from_secs(300), Duration::ZERO);
+        let now = Instant::now();
+        fetcher.cache.remember("unknown", &None, now);
+        assert_eq!(fetcher.cache.cached("unknown", now), None);
+    }
+
+    #[tokio::test]
+    async fn test_burst_for_a_new_bucket_calls_back_once() {
+        let (fetcher, calls) = fetcher(Duration::from_secs(300), Duration::from_secs(30));
+        let results = futures::future::join_all((0..20).map(|_| fetcher.get("known"))).await;
+        assert!(
+            results
+                .iter()
+                .all(|api_key| api_key.as_ref().unwrap().as_deref() == Some("api-key"))
+        );
+        assert_eq!(call_count(&calls), 1);
+    }
+}
@@ -1 +1,9 @@
+pub mod bucket_creds;
+pub mod redis_store;
+pub mod secret_source;
 pub mod secrets_proxy;
+pub mod signer;
+pub mod token_client;
+pub mod token_file;
+pub mod token_provider;
+pub mod token_store;
@@ -0,0 +1,348 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pyo3::prelude::*;
+use tracing::{info, warn};
+
+use crate::credentials::secrets_proxy::SecretsCache;
+
+/// How often secret files are checked for changes.
+const FILE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+static FILE_SECRETS: OnceLock<FileSecrets> = OnceLock::new();
+
+/// A secret in the cos map: the value itself, or a reference to where it is kept, written
+/// as `env:<VARIABLE>`, `file:<path>` or `dir:<directory>`. The last names a watched
+/// directory holding a file per bucket, so `dir:` sources are bound to their bucket (see
+/// `for_bucket`) before they are resolved.
+#[derive(Clone, PartialEq)]
+pub enum SecretSource {
+    Inline(String),
+    Env(String),
+    File(PathBuf),
+    Dir(PathBuf),
+    /// the file of a bucket in a `dir:` directory, which need not exist yet
+    DirEntry(PathBuf),
+}
+
+impl SecretSource {
+    pub fn parse(value: &str) -> Self {
+        if let Some(name) = value.strip_prefix("env:") {
+            SecretSource::Env(name.to_string())
+        } else if let Some(path) = value.strip_prefix("file:") {
+            SecretSource::File(PathBuf::from(path))
+        } else if let Some(dir) = value.strip_prefix("dir:") {
+            SecretSource::Dir(PathBuf::from(dir))
+        } else {
+            SecretSource::Inline(value.to_string())
+        }
+    }
+
+    /// The secret of `bucket`: its file in a `dir:` directory, the same secret for the other
+    /// sources. Names that are no plain file name are left unbound, so they resolve to none.
+    pub fn for_bucket(&self, bucket: &str) -> Self {
+        match self {
+            SecretSource::Dir(dir)
+                if !bucket.is_empty()
+                    && bucket != "."
+                    && bucket != ".."
+                    && !bucket.contains(['/', '\\']) =>
+            {
+                SecretSource::DirEntry(dir.join(bucket))
+            }
+            source => source.clone(),
+        }
+    }
+
+    /// The current value of the secret. Files are not read here but when they are loaded
+    /// (see `preload`), and then kept up to date by the `SecretFileWatcher`.
+    pub fn resolve(&self) -> Result<String, String> {
+        match self {
+            SecretSource::Inline(value) => Ok(value.clone()),
+            SecretSource::Env(name) => {
+                std::env::var(name).map_err(|_| format!("Environment variable {} is not set", name))
+            }
+            SecretSource::File(path) | SecretSource::DirEntry(path) => {
+                FileSecrets::shared().read(path)
+            }
+            SecretSource::Dir(dir) => Err(format!(
+                "Secret directory {} is not bound to a bucket",
+                dir.display()
+            )),
+        }
+    }
+
+    /// What tokens fetched with the secret are cached by: the value of an inline secret,
+    /// the reference otherwise, so that it need not be read to find the token.
+    pub fn cache_id(&self) -> String {
+        match self {
+            SecretSource::Inline(value) => value.clone(),
+            SecretSource::Env(name) => format!("env:{}", name),
+            SecretSource::File(path) => format!("file:{}", path.display()),
+            SecretSource::Dir(path) | SecretSource::DirEntry(path) => {
+                format!("dir:{}", path.display())
+            }
+        }
+    }
+
+    /// Reads a secret file ahead of its first use and has it watched; requests only see
+    /// the files loaded this way. Blocks, so it runs at startup or on a blocking thread.
+    /// Files of a `dir:` directory may be missing, they are picked up once they appear.
+    pub fn preload(&self) -> Result<(), String> {
+        match self {
+            SecretSource::File(path) => FileSecrets::shared().load(path, true),
+            SecretSource::DirEntry(path) => FileSecrets::shared().load(path, false),
+            _ => Ok(()),
+        }
+    }
+
+    /// Has the token cached under `cache_key` dropped when the secret file changes.
+    pub fn invalidates(&self, cache_key: &str) {
+        if let SecretSource::File(path) | SecretSource::DirEntry(path) = self {
+            FileSecrets::shared().add_dependent(path, cache_key);
+        }
+    }
+}
+
+impl std::fmt::Debug for SecretSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretSource::Inline(_) => f.write_str("Inline(..)"),
+            SecretSource::Env(name) => write!(f, "Env({})", name),
+            SecretSource::File(path) => write!(f, "File({})", path.display()),
+            SecretSource::Dir(path) => write!(f, "Dir({})", path.display()),
+            SecretSource::DirEntry(path) => write!(f, "DirEntry({})", path.display()),
+        }
+    }
+}
+
+impl<'py> FromPyObject<'py> for SecretSource {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        Ok(SecretSource::parse(&ob.extract::<String>()?))
+    }
+}
+
+#[derive(Debug)]
+struct WatchedFile {
+    /// `None` while the file of a `dir:` source does not exist
+    value: Option<String>,
+    /// keys of the tokens fetched with the secret
+    dependents: HashSet<String>,
+}
+
+/// The contents of the secret files referenced in the cos map.
+#[derive(Debug, Default)]
+pub struct FileSecrets {
+    files: RwLock<HashMap<PathBuf, WatchedFile>>,
+}
+
+fn read_secret(path: &Path) -> Result<String, String> {
+    std::fs::read_to_string(path)
+        .map(|contents| contents.trim().to_string())
+        .map_err(|e| format!("Failed to read secret file {}: {}", path.display(), e))
+}
+
+impl FileSecrets {
+    pub fn shared() -> &'static FileSecrets {
+        FILE_SECRETS.get_or_init(FileSecrets::default)
+    }
+
+    /// Reads a file and watches it from then on. A missing file is an error when it is
+    /// `required`, otherwise it is watched for until it appears.
+    fn load(&self, path: &Path, required: bool) -> Result<(), String> {
+        if let Some(file) = self.files.read().unwrap().get(path)
+            && (file.value.is_some() || !required)
+        {
+            return Ok(());
+        }
+        let value = match read_secret(path) {
+            Ok(value) => Some(value),
+            Err(e) if required => return Err(e),
+            Err(_) => None,
+        };
+        let mut files = self.files.write().unwrap();
+        let file = files
+            .entry(path.to_path_buf())
+            .or_insert_with(|| WatchedFile {
+                value: None,
+                dependents: HashSet::new(),
+            });
+        if file.value.is_none() {
+            file.value = value;
+        }
+        Ok(())
+    }
+
+    /// The loaded value of a file; never touches the file system.
+    fn read(&self, path: &Path) -> Result<String, String> {
+        match self.files.read().unwrap().get(path) {
+            Some(WatchedFile {
+                value: Some(value), ..
+            }) => Ok(value.clone()),
+            Some(_) => Err(format!("Secret file {} does not exist", path.display())),
+            None => Err(format!("Secret file {} was not loaded", path.display())),
+        }
+    }
+
+    fn add_dependent(&self, path: &Path, cache_key: &str) {
+        if let Some(file) = self.files.write().unwrap().get_mut(path) {
+            file.dependents.insert(cache_key.to_string());
+        }
+    }
+
+    /// Re-reads the secret files and returns the keys of the tokens fetched with the ones
+    /// that changed. Files that cannot be read keep their last value.
+    pub fn reload(&self) -> Vec<String> {
+        let paths: Vec<PathBuf> = self.files.read().unwrap().keys().cloned().collect();
+        let mut changed = Vec::new();
+        for path in paths {
+            let value = read_secret(&path);
+            let mut files = self.files.write().unwrap();
+            let Some(file) = files.get_mut(&path) else {
+                continue;
+            };
+            match value {
+                Ok(value) if file.value.as_ref() != Some(&value) => {
+                    if file.value.is_some() {
+                        info!("Secret file {} changed", path.display());
+                    } else {
+                        info!("Secret file {} appeared", path.display());
+                    }
+                    file.value = Some(value);
+                    changed.extend(file.dependents.iter().cloned());
+                }
+                Ok(_) => {}
+                Err(e) if file.value.is_some() => warn!("{}; keeping the current secret", e),
+                // a `dir:` file that is still missing
+                Err(_) => {}
+            }
+        }
+        changed
+    }
+}
+
+/// Picks up rotated secret files, and drops the tokens fetched with the old secrets.
+pub struct SecretFileWatcher {
+    cache: SecretsCache,
+}
+
+impl SecretFileWatcher {
+    pub fn new(cache: SecretsCache) -> Self {
+        SecretFileWatcher { cache }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for SecretFileWatcher {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(FILE_POLL_INTERVAL) => {}
+                _ = shutdown.changed() => return,
+            }
+            let changed = tokio::task::spawn_blocking(|| FileSecrets::shared().reload())
+                .await
+                .unwrap_or_default();
+            for key in changed {
+                info!("Dropping token {} fetched with a rotated secret", key);
+                self.cache.invalidate(&key).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            SecretSource::parse("env:COS_KEY_A"),
+            SecretSource::Env("COS_KEY_A".to_string())
+        );
+        assert_eq!(
+            SecretSource::parse("file:/run/secrets/a"),
+            SecretSource::File(PathBuf::from("/run/secrets/a"))
+        );
+        assert_eq!(
+            SecretSource::parse("plain-api-key"),
+            SecretSource::Inline("plain-api-key".to_string())
+        );
+        assert_eq!(
+            format!("{:?}", SecretSource::parse("plain-api-key")),
+            "Inline(..)"
+        );
+    }
+
+    #[test]
+    fn test_resolve_env() {
+        let source = SecretSource::parse("env:OSP_TEST_SECRET_SOURCE");
+        assert!(source.resolve().is_err());
+        // SAFETY: no other test reads or writes this variable
+        unsafe { std::env::set_var("OSP_TEST_SECRET_SOURCE", "key-a") };
+        assert_eq!(source.resolve().unwrap(), "key-a");
+        assert_eq!(source.cache_id(), "env:OSP_TEST_SECRET_SOURCE");
+    }
+
+    #[test]
+    fn test_rotated_file_invalidates_dependents() {
+        let path = std::env::temp_dir().join(format!("osp-{}-secret-source", std::process::id()));
+        std::fs::write(&path, "key-a\n").unwrap();
+        let secrets = FileSecrets::default();
+        assert!(secrets.read(&path).is_err());
+        secrets.load(&path, true).unwrap();
+        assert_eq!(secrets.read(&path).unwrap(), "key-a");
+        secrets.add_dependent(&path, "apikey:1");
+        assert!(secrets.reload().is_empty());
+
+        std::fs::write(&path, "key-b\n").unwrap();
+        assert_eq!(secrets.read(&path).unwrap(), "key-a");
+        assert_eq!(secrets.reload(), vec!["apikey:1".to_string()]);
+        assert_eq!(secrets.read(&path).unwrap(), "key-b");
+
+        // a missing file keeps its last value
+        std::fs::remove_file(&path).unwrap();
+        assert!(secrets.reload().is_empty());
+        assert_eq!(secrets.read(&path).unwrap(), "key-b");
+    }
+
+    #[test]
+    fn test_dir_entries_are_picked_up_when_they_appear() {
+        let dir = std::env::temp_dir().join(format!("osp-{}-secret-dir", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = SecretSource::parse(&format!("dir:{}", dir.display()));
+        assert_eq!(source, SecretSource::Dir(dir.clone()));
+        assert!(source.resolve().is_err());
+        assert_eq!(source.for_bucket("../etc"), source);
+
+        let bucket1 = source.for_bucket("bucket1");
+        assert_eq!(bucket1, SecretSource::DirEntry(dir.join("bucket1")));
+        assert_eq!(
+            bucket1.cache_id(),
+            format!("dir:{}", dir.join("bucket1").display())
+        );
+
+        let secrets = FileSecrets::default();
+        let path = dir.join("bucket1");
+        assert!(secrets.load(&path, true).is_err());
+        secrets.load(&path, false).unwrap();
+        assert!(secrets.read(&path).is_err());
+        assert!(secrets.reload().is_empty());
+
+        std::fs::write(&path, "key-a\n").unwrap();
+        secrets.add_dependent(&path, "apikey:1");
+        assert_eq!(secrets.reload(), vec!["apikey:1".to_string()]);
+        assert_eq!(secrets.read(&path).unwrap(), "key-a");
+
+        std::fs::write(&path, "key-b").unwrap();
+        assert_eq!(secrets.reload(), vec!["apikey:1".to_string()]);
+        assert_eq!(secrets.read(&path).unwrap(), "key-b");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
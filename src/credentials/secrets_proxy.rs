@@ -1,13 +1,71 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, RwLock},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 
-use reqwest::Client;
-use serde::Deserialize;
+use async_trait::async_trait;
+use openssl::rand::rand_bytes;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
 use tracing::{error, info, warn};
 
-#[derive(Clone, Debug)]
+use crate::credentials::token_client::TokenClient;
+use crate::credentials::token_provider::{TokenError, TokenProvider};
+use crate::credentials::token_store::{MemoryTokenStore, StoreError, TokenStore};
+
+/// The public IBM Cloud IAM token endpoint.
+pub const IBM_IAM_URL: &str = "https://iam.cloud.ibm.com/identity/token";
+
+/// Tokens are renewed on the request path once they have less than this many seconds left.
+const EXPIRY_BUFFER_SECS: u64 = 300;
+/// The `TokenRefresher` renews tokens this many seconds before they expire, well before
+/// requests would have to.
+const REFRESH_MARGIN_SECS: u64 = 900;
+/// Up to this many seconds are added to the refresh margin, so tokens fetched together are
+/// not all renewed at the same moment.
+const REFRESH_JITTER_SECS: u64 = 300;
+/// How soon the `TokenRefresher` tries again after failing to renew a token.
+const REFRESH_RETRY_SECS: u64 = 30;
+/// How long a proxy may hold the lock on renewing a token in a shared store; others wait
+/// this long at most before fetching the token themselves.
+const LOCK_TTL: Duration = Duration::from_secs(30);
+/// How often proxies waiting for another one to renew a token look for the new token.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Bound on each call to the store; one that does not answer in time counts as unreachable,
+/// so requests go on with the tokens in the local tier rather than wait for it.
+const STORE_TIMEOUT: Duration = Duration::from_millis(500);
+/// After dropping a token the upstream rejected, tokens for the same credential are kept
+/// this long even when rejected too; the credential, not the token, is evidently the problem.
+const REJECTED_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// The key a token fetched with `api_key` is cached under. Buckets sharing an API key
+/// share its token, and the API key itself is not kept around.
+pub fn credential_key(api_key: &str) -> String {
+    format!("apikey:{}", hex::encode(&Sha256::digest(api_key)[..16]))
+}
+
+pub(crate) fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// `call` to the store, failed when it takes longer than `STORE_TIMEOUT`.
+async fn bounded<T>(
+    call: impl std::future::Future<Output = Result<T, StoreError>>,
+) -> Result<T, StoreError> {
+    tokio::time::timeout(STORE_TIMEOUT, call)
+        .await
+        .unwrap_or_else(|_| Err("the token store did not answer in time".into()))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SecretValue {
     value: String,
     expiration: u64,
@@ -27,91 +85,388 @@ impl SecretValue {
     }
 
     pub fn is_expired(&self) -> bool {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        now >= self.expiration - 300 // 5 minute buffer
+        self.expires_within(EXPIRY_BUFFER_SECS)
+    }
+
+    /// Whether the token has less than `secs` seconds left.
+    pub fn expires_within(&self, secs: u64) -> bool {
+        now_secs() + secs >= self.expiration
+    }
+}
+
+impl From<IamResponse> for SecretValue {
+    fn from(iam_response: IamResponse) -> Self {
+        SecretValue::new(iam_response.access_token, iam_response.expiration)
     }
 }
 
+/// A token fetch in progress, shared by everyone asking for the same key meanwhile.
+type Flight = Arc<OnceCell<Option<SecretValue>>>;
+
+/// Bearer tokens by cache key. Tokens are served from an in-process copy, so that the store
+/// (which may be shared with other proxies, over the network) is only consulted when a
+/// token is missing or due for renewal, and a store that is down does not hold up requests.
 #[derive(Clone, Debug)]
 pub struct SecretsCache {
-    inner: Arc<RwLock<HashMap<String, SecretValue>>>,
+    store: Arc<dyn TokenStore>,
+    /// The tokens last seen in, or written to, the store
+    local: Arc<RwLock<HashMap<String, SecretValue>>>,
+    in_flight: Arc<Mutex<HashMap<String, Flight>>>,
+    /// Identifies this proxy as the holder of refresh locks in a shared store
+    owner: String,
+    /// When tokens the upstream rejected were last dropped, by key
+    rejected: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl Default for SecretsCache {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SecretsCache {
     pub fn new() -> Self {
+        Self::with_store(Arc::new(MemoryTokenStore::new()))
+    }
+
+    pub fn with_store(store: Arc<dyn TokenStore>) -> Self {
+        let mut owner = [0u8; 16];
+        rand_bytes(&mut owner).expect("the system has a random number generator");
         SecretsCache {
-            inner: Arc::new(RwLock::new(HashMap::new())),
+            store,
+            local: Arc::new(RwLock::new(HashMap::new())),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            owner: hex::encode(owner),
+            rejected: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    pub fn insert(&self, key: String, value: String, expiration: u64) {
+    pub async fn insert(&self, key: String, value: String, expiration: u64) {
         let secret = SecretValue { value, expiration };
+        if let Err(e) = bounded(self.store.put(&key, &secret)).await {
+            error!("Failed to store token for {}: {}", key, e);
+        }
+        self.local.write().unwrap().insert(key, secret);
+    }
 
-        let mut map = self.inner.write().unwrap();
-        map.insert(key, secret);
+    /// The token for `key` in the store, expired or not; the one last seen when the store
+    /// cannot be reached.
+    pub async fn peek(&self, key: &str) -> Option<SecretValue> {
+        match bounded(self.store.get(key)).await {
+            Ok(secret) => {
+                let mut local = self.local.write().unwrap();
+                match &secret {
+                    Some(secret) => local.insert(key.to_string(), secret.clone()),
+                    None => local.remove(key),
+                };
+                secret
+            }
+            Err(e) => {
+                warn!("Failed to look up token for {}: {}", key, e);
+                self.local.read().unwrap().get(key).cloned()
+            }
+        }
     }
 
-    pub async fn get<F, Fut>(&self, key: &str, bearer_fetcher: F) -> Option<String>
+    /// Returns the cached token for `key`, fetching a new one when there is none or it
+    /// is about to expire. Concurrent callers for the same key share a single fetch and
+    /// all get its outcome, so an expiring token under load costs one IAM call.
+    pub async fn get<F, Fut, E>(&self, key: &str, bearer_fetcher: F) -> Option<String>
     where
         F: Fn() -> Fut + Send + 'static,
-        Fut: std::future::Future<Output = Result<IamResponse, Box<dyn std::error::Error>>> + Send,
+        Fut: std::future::Future<Output = Result<SecretValue, E>> + Send,
+        E: std::fmt::Display,
+    {
+        let local = self.local.read().unwrap().get(key).cloned();
+        if let Some(secret) = local.filter(|secret| !secret.is_expired()) {
+            info!("Using cached token for {}", key);
+            return Some(secret.value);
+        }
+        self.renew(key, bearer_fetcher, SecretValue::is_expired)
+            .await
+            .map(|secret| secret.value)
+    }
+
+    /// Fetches a new token for `key` unless the cached one is no longer `stale`, sharing the
+    /// fetch with concurrent callers. When the fetch fails, the current token keeps being
+    /// served for as long as it is actually valid.
+    pub async fn renew<F, Fut, E, S>(
+        &self,
+        key: &str,
+        bearer_fetcher: F,
+        stale: S,
+    ) -> Option<SecretValue>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<SecretValue, E>>,
+        E: std::fmt::Display,
+        S: Fn(&SecretValue) -> bool,
     {
-        let maybe_secret = {
-            let map = self.inner.read().unwrap();
-            map.get(key).cloned()
+        let flight = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            in_flight.entry(key.to_string()).or_default().clone()
         };
 
-        match maybe_secret {
-            Some(secret) => {
-                if secret.is_expired() {
-                    info!("Token for {} is expired, renewing ...", key);
-                    match bearer_fetcher().await {
-                        Ok(iam_response) => {
-                            self.insert(
-                                key.to_string(),
-                                iam_response.access_token.clone(),
-                                iam_response.expiration,
-                            );
-                            println!("Renewed token for {}", key);
-                            Some(iam_response.access_token)
-                        }
-                        Err(e) => {
-                            error!("Failed to renew token for {}: {}", key, e);
-                            None
+        let secret = flight
+            .get_or_init(|| async {
+                let Some(locked) = self.lock(key, &stale).await else {
+                    // another proxy has renewed it, or is renewing it and the current one
+                    // is still good
+                    return self.peek(key).await;
+                };
+                let secret = self.fetch(key, bearer_fetcher).await;
+                if locked && let Err(e) = bounded(self.store.unlock(key, &self.owner)).await {
+                    warn!("Failed to release the refresh lock on {}: {}", key, e);
+                }
+                secret
+            })
+            .await
+            .clone();
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, &flight))
+        {
+            in_flight.remove(key);
+        }
+
+        secret
+    }
+
+    /// Takes the store's refresh lock on `key`, unless the cached token is no longer `stale`
+    /// (another flight, or another proxy, may have replaced it since we looked). Returns
+    /// `None` when there is no need to fetch a token, and otherwise whether we hold the lock.
+    async fn lock<S>(&self, key: &str, stale: &S) -> Option<bool>
+    where
+        S: Fn(&SecretValue) -> bool,
+    {
+        let deadline = Instant::now() + LOCK_TTL;
+        loop {
+            let current = self.peek(key).await;
+            if current.as_ref().is_some_and(|secret| !stale(secret)) {
+                return None;
+            }
+            match bounded(self.store.try_lock(key, &self.owner, LOCK_TTL)).await {
+                // it may have been renewed between our look and taking the lock
+                Ok(true) => match self.peek(key).await {
+                    Some(secret) if !stale(&secret) => {
+                        if let Err(e) = bounded(self.store.unlock(key, &self.owner)).await {
+                            warn!("Failed to release the refresh lock on {}: {}", key, e);
                         }
+                        return None;
                     }
-                } else {
-                    info!("Using cached token for {}", key);
-                    Some(secret.get_value().to_string())
+                    _ => return Some(true),
+                },
+                Ok(false)
+                    if current
+                        .as_ref()
+                        .is_some_and(|secret| !secret.expires_within(0)) =>
+                {
+                    info!("Token for {} is being renewed by another proxy", key);
+                    return None;
+                }
+                Ok(false) if Instant::now() < deadline => {
+                    tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+                }
+                Ok(false) => {
+                    warn!(
+                        "Gave up waiting for another proxy to renew the token for {}",
+                        key
+                    );
+                    return Some(false);
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to take the refresh lock on {}, renewing anyway: {}",
+                        key, e
+                    );
+                    return Some(false);
                 }
             }
-            None => {
-                info!("No cached token found for {}, fetching ...", key);
-                match bearer_fetcher().await {
-                    Ok(iam_response) => {
-                        self.insert(
-                            key.to_string(),
-                            iam_response.access_token.clone(),
-                            iam_response.expiration,
-                        );
-                        info!("Fetched new token for {}", key);
-                        Some(iam_response.access_token)
-                    }
-                    Err(e) => {
-                        error!("Failed to fetch token for {}: {}", key, e);
-                        None
-                    }
+        }
+    }
+
+    async fn fetch<F, Fut, E>(&self, key: &str, bearer_fetcher: F) -> Option<SecretValue>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<SecretValue, E>>,
+        E: std::fmt::Display,
+    {
+        info!("Fetching a new token for {} ...", key);
+        match bearer_fetcher().await {
+            Ok(secret) => {
+                self.insert(key.to_string(), secret.value.clone(), secret.expiration)
+                    .await;
+                info!("Fetched new token for {}", key);
+                Some(secret)
+            }
+            Err(e) => match self
+                .peek(key)
+                .await
+                .filter(|secret| !secret.expires_within(0))
+            {
+                Some(secret) => {
+                    warn!(
+                        "Failed to renew token for {}, serving the current one: {}",
+                        key, e
+                    );
+                    Some(secret)
+                }
+                None => {
+                    error!("Failed to fetch token for {}: {}", key, e);
+                    None
                 }
+            },
+        }
+    }
+
+    pub async fn invalidate(&self, key: &str) {
+        self.local.write().unwrap().remove(key);
+        if let Err(e) = bounded(self.store.remove(key)).await {
+            error!("Failed to drop token for {}: {}", key, e);
+        }
+    }
+
+    /// Drops `token`, cached for `key`, after the upstream rejected it, so that the next
+    /// request fetches a new one. Returns `false`, keeping it, when a token for `key` was
+    /// already dropped less than `REJECTED_COOLDOWN` ago.
+    pub async fn invalidate_rejected(&self, key: &str, token: &str) -> bool {
+        {
+            let now = Instant::now();
+            let mut rejected = self.rejected.lock().unwrap();
+            if rejected
+                .get(key)
+                .is_some_and(|at| now < *at + REJECTED_COOLDOWN)
+            {
+                return false;
+            }
+            rejected.retain(|_, at| now < *at + REJECTED_COOLDOWN);
+            rejected.insert(key.to_string(), now);
+        }
+        // unless it has been replaced meanwhile
+        if self
+            .peek(key)
+            .await
+            .is_some_and(|secret| secret.value == token)
+        {
+            self.invalidate(key).await;
+        }
+        true
+    }
+
+    /// Drops the token fetched with `api_key`, for every bucket using it.
+    pub async fn invalidate_credential(&self, api_key: &str) {
+        self.invalidate(&credential_key(api_key)).await;
+    }
+}
+
+/// Keeps the tokens of the configured buckets fresh in the background: fetches them all at
+/// startup, then renews each one `REFRESH_MARGIN_SECS` plus some jitter before it expires,
+/// retrying every `REFRESH_RETRY_SECS` while renewing fails. Requests keep using the
+/// current token meanwhile.
+pub struct TokenRefresher {
+    cache: SecretsCache,
+    /// providers by the key their tokens are cached under
+    providers: HashMap<String, Arc<dyn TokenProvider>>,
+}
+
+impl TokenRefresher {
+    pub fn new(cache: SecretsCache, providers: Vec<Arc<dyn TokenProvider>>) -> Self {
+        TokenRefresher {
+            cache,
+            providers: providers
+                .into_iter()
+                .map(|provider| (provider.cache_key(), provider))
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// How many seconds before it expires the token of `key` is renewed; stable for a
+    /// given token but different between keys.
+    fn margin(key: &str, secret: &SecretValue) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (key, secret.expiration).hash(&mut hasher);
+        REFRESH_MARGIN_SECS + hasher.finish() % (REFRESH_JITTER_SECS + 1)
+    }
+
+    fn refresh_at(key: &str, secret: &SecretValue) -> u64 {
+        secret.expiration.saturating_sub(Self::margin(key, secret))
+    }
+
+    /// Renews the tokens that are due at `now` and returns when the next one is due.
+    async fn refresh_due(&self, now: u64) -> u64 {
+        let refreshes = self.providers.iter().map(|(key, provider)| async move {
+            let current = self.cache.peek(key).await;
+            if let Some(secret) = &current
+                && Self::refresh_at(key, secret) > now
+            {
+                return Self::refresh_at(key, secret);
+            }
+
+            let expiration = current.map_or(0, |secret| secret.expiration);
+            let renewed = self
+                .cache
+                .renew(
+                    key,
+                    || provider.fetch_token(),
+                    |secret| {
+                        // unless someone else has replaced it meanwhile
+                        secret.expiration <= expiration
+                    },
+                )
+                .await;
+            let retry_at = now + REFRESH_RETRY_SECS;
+            renewed.map_or(retry_at, |secret| {
+                Self::refresh_at(key, &secret).max(retry_at)
+            })
+        });
+        futures::future::join_all(refreshes)
+            .await
+            .into_iter()
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+}
+
+#[async_trait]
+impl BackgroundService for TokenRefresher {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        info!("Refreshing tokens for {} credentials", self.providers.len());
+        loop {
+            let next = self.refresh_due(now_secs()).await;
+            let sleep = Duration::from_secs(next.saturating_sub(now_secs()));
+            tokio::select! {
+                _ = tokio::time::sleep(sleep) => {}
+                _ = shutdown.changed() => return,
             }
         }
     }
+}
+
+/// Writes out the tokens of a store that writes behind when the server shuts down, as the
+/// store itself is not dropped on exit.
+pub struct TokenStoreFlusher {
+    cache: SecretsCache,
+}
 
-    pub fn invalidate(&self, key: &str) {
-        let mut map = self.inner.write().unwrap();
-        map.remove(key);
+impl TokenStoreFlusher {
+    pub fn new(cache: SecretsCache) -> Self {
+        TokenStoreFlusher { cache }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for TokenStoreFlusher {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let _ = shutdown.changed().await;
+        if let Err(e) = self.cache.store.flush().await {
+            error!("Failed to write out the token cache on shutdown: {}", e);
+        }
     }
 }
 
@@ -122,39 +477,26 @@ pub struct IamResponse {
     pub expiration: u64,
 }
 
-pub(crate) async fn get_bearer(api_key: String) -> Result<IamResponse, Box<dyn std::error::Error>> {
+/// Exchanges `api_key` for a bearer token at the IAM token endpoint `url`.
+pub(crate) async fn get_bearer(url: &str, api_key: &str) -> Result<IamResponse, TokenError> {
     info!("Fetching bearer token for the API key");
-    let client = Client::new();
 
     let params = [
         ("grant_type", "urn:ibm:params:oauth:grant-type:apikey"),
-        ("apikey", &api_key),
+        ("apikey", api_key),
     ];
 
-    // todo: move url to config
-    let resp = client
-        .post("https://iam.cloud.ibm.com/identity/token")
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .form(&params)
-        .send()
-        .await?;
-
-    if resp.status().is_success() {
-        // println!("Response: {:?}", resp);
-        let iam_response: IamResponse = resp.json().await?;
-        // println!("Received access token: {:?}", iam_response);
-        info!("Received access token");
-        Ok(iam_response)
-    } else {
-        let err_text = resp.text().await?;
-        error!("Failed to get token: {}", err_text);
-        Err(format!("Failed to get token: {}", err_text).into())
-    }
+    let body = TokenClient::shared().post_form(url, &params, None).await?;
+    let iam_response: IamResponse = serde_json::from_str(&body)?;
+    info!("Received access token");
+    Ok(iam_response)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
     use wiremock::matchers::{method, path};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
@@ -227,6 +569,379 @@ mod tests {
         );
     }
 
+    fn token(value: &str) -> SecretValue {
+        SecretValue::new(value.to_string(), 9999999999)
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_gets_share_one_fetch() {
+        let cache = SecretsCache::new();
+        let fetches = Arc::new(AtomicUsize::new(0));
+
+        let gets = (0..10).map(|_| {
+            let fetches = fetches.clone();
+            cache.get("bucket01", move || {
+                let fetches = fetches.clone();
+                async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Ok::<_, TokenError>(token("token"))
+                }
+            })
+        });
+        let tokens = futures::future::join_all(gets).await;
+
+        assert!(tokens.iter().all(|t| t.as_deref() == Some("token")));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_gets_share_one_failure() {
+        let cache = SecretsCache::new();
+        let fetches = Arc::new(AtomicUsize::new(0));
+
+        let fetcher = |fetches: Arc<AtomicUsize>| {
+            move || {
+                let fetches = fetches.clone();
+                async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Err::<SecretValue, _>("IAM is down")
+                }
+            }
+        };
+        let gets = (0..5).map(|_| cache.get("bucket01", fetcher(fetches.clone())));
+        let tokens = futures::future::join_all(gets).await;
+        assert!(tokens.iter().all(Option::is_none));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // a failure is not remembered, the next caller tries again
+        assert_eq!(cache.get("bucket01", fetcher(fetches.clone())).await, None);
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    fn expiring_in(secs: u64) -> u64 {
+        now_secs() + secs
+    }
+
+    #[tokio::test]
+    async fn test_failed_renewal_keeps_valid_token() {
+        let cache = SecretsCache::new();
+        let failing = || async { Err::<SecretValue, _>("IAM is down") };
+
+        cache
+            .insert("bucket01".to_string(), "old".to_string(), expiring_in(120))
+            .await;
+        assert_eq!(cache.get("bucket01", failing).await.as_deref(), Some("old"));
+
+        cache
+            .insert("bucket01".to_string(), "old".to_string(), expiring_in(0))
+            .await;
+        assert_eq!(cache.get("bucket01", failing).await, None);
+    }
+
+    #[derive(Debug)]
+    struct CountingProvider {
+        key: &'static str,
+        fetches: AtomicUsize,
+        fail: bool,
+    }
+
+    impl CountingProvider {
+        fn new(key: &'static str, fail: bool) -> Arc<Self> {
+            Arc::new(CountingProvider {
+                key,
+                fetches: AtomicUsize::new(0),
+                fail,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl TokenProvider for CountingProvider {
+        fn cache_key(&self) -> String {
+            self.key.to_string()
+        }
+
+        async fn fetch_token(&self) -> Result<SecretValue, TokenError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            if self.fail {
+                return Err("IAM is down".into());
+            }
+            Ok(SecretValue::new(
+                format!("token-{}", self.key),
+                expiring_in(3600),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresher_warms_up_and_renews_ahead_of_expiry() {
+        let cache = SecretsCache::new();
+        let first = CountingProvider::new("key1", false);
+        let second = CountingProvider::new("key2", false);
+        let refresher = TokenRefresher::new(cache.clone(), vec![first.clone(), second.clone()]);
+        let fetches =
+            || first.fetches.load(Ordering::SeqCst) + second.fetches.load(Ordering::SeqCst);
+
+        let now = now_secs();
+        let next = refresher.refresh_due(now).await;
+        assert_eq!(fetches(), 2);
+        assert_eq!(cache.peek("key2").await.unwrap().get_value(), "token-key2");
+        assert!(next >= now + 3600 - REFRESH_MARGIN_SECS - REFRESH_JITTER_SECS);
+        assert!(next <= now + 3600 - REFRESH_MARGIN_SECS);
+
+        // nothing is due yet
+        refresher.refresh_due(now).await;
+        assert_eq!(fetches(), 2);
+
+        // both are due once we are past the latest refresh time
+        refresher
+            .refresh_due(now + 3600 - REFRESH_MARGIN_SECS)
+            .await;
+        assert_eq!(fetches(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_refresher_retries_failures() {
+        let cache = SecretsCache::new();
+        cache
+            .insert("key1".to_string(), "old".to_string(), expiring_in(600))
+            .await;
+        let refresher =
+            TokenRefresher::new(cache.clone(), vec![CountingProvider::new("key1", true)]);
+
+        let now = now_secs();
+        assert_eq!(refresher.refresh_due(now).await, now + REFRESH_RETRY_SECS);
+        assert_eq!(cache.peek("key1").await.unwrap().get_value(), "old");
+    }
+
+    #[tokio::test]
+    async fn test_replicas_sharing_a_store_fetch_once() {
+        let store: Arc<dyn TokenStore> = Arc::new(MemoryTokenStore::new());
+        let replicas: Vec<_> = (0..3)
+            .map(|_| SecretsCache::with_store(store.clone()))
+            .collect();
+        let fetches = Arc::new(AtomicUsize::new(0));
+
+        let gets = replicas.iter().map(|cache| {
+            let fetches = fetches.clone();
+            cache.get("key1", move || {
+                let fetches = fetches.clone();
+                async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    Ok::<_, TokenError>(SecretValue::new("token1".to_string(), expiring_in(3600)))
+                }
+            })
+        });
+        let tokens = futures::future::join_all(gets).await;
+
+        assert!(tokens.iter().all(|t| t.as_deref() == Some("token1")));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_replica_serves_current_token_while_another_renews() {
+        let store: Arc<dyn TokenStore> = Arc::new(MemoryTokenStore::new());
+        let cache = SecretsCache::with_store(store.clone());
+        cache
+            .insert("key1".to_string(), "old".to_string(), expiring_in(120))
+            .await;
+        assert!(
+            store
+                .try_lock("key1", "other proxy", LOCK_TTL)
+                .await
+                .unwrap()
+        );
+
+        let failing = || async { Err::<SecretValue, _>("not expected to be called") };
+        assert_eq!(cache.get("key1", failing).await.as_deref(), Some("old"));
+    }
+
+    /// A shared store that can be taken down or stop answering lookups and locks, counting
+    /// the lookups that reach it.
+    #[derive(Debug, Default)]
+    struct FlakyStore {
+        inner: MemoryTokenStore,
+        down: std::sync::atomic::AtomicBool,
+        hanging: std::sync::atomic::AtomicBool,
+        gets: AtomicUsize,
+    }
+
+    impl FlakyStore {
+        async fn stall(&self) {
+            if self.hanging.load(Ordering::SeqCst) {
+                std::future::pending::<()>().await;
+            }
+        }
+
+        fn check(&self) -> Result<(), crate::credentials::token_store::StoreError> {
+            match self.down.load(Ordering::SeqCst) {
+                true => Err("store is down".into()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl TokenStore for FlakyStore {
+        async fn get(
+            &self,
+            key: &str,
+        ) -> Result<Option<SecretValue>, crate::credentials::token_store::StoreError> {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            self.stall().await;
+            self.check()?;
+            self.inner.get(key).await
+        }
+
+        async fn put(
+            &self,
+            key: &str,
+            secret: &SecretValue,
+        ) -> Result<(), crate::credentials::token_store::StoreError> {
+            self.check()?;
+            self.inner.put(key, secret).await
+        }
+
+        async fn remove(
+            &self,
+            key: &str,
+        ) -> Result<(), crate::credentials::token_store::StoreError> {
+            self.check()?;
+            self.inner.remove(key).await
+        }
+
+        async fn try_lock(
+            &self,
+            key: &str,
+            owner: &str,
+            ttl: Duration,
+        ) -> Result<bool, crate::credentials::token_store::StoreError> {
+            self.stall().await;
+            self.check()?;
+            self.inner.try_lock(key, owner, ttl).await
+        }
+
+        async fn unlock(
+            &self,
+            key: &str,
+            owner: &str,
+        ) -> Result<(), crate::credentials::token_store::StoreError> {
+            self.check()?;
+            self.inner.unlock(key, owner).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tokens_are_served_locally() {
+        let store = Arc::new(FlakyStore::default());
+        let cache = SecretsCache::with_store(store.clone());
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let fetcher = |fetches: Arc<AtomicUsize>| {
+            move || {
+                let fetches = fetches.clone();
+                async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, TokenError>(SecretValue::new("token1".to_string(), expiring_in(3600)))
+                }
+            }
+        };
+
+        for _ in 0..5 {
+            let token = cache.get("key1", fetcher(fetches.clone())).await;
+            assert_eq!(token.as_deref(), Some("token1"));
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        // only the first lookup, on the miss, went to the store
+        let gets = store.gets.load(Ordering::SeqCst);
+        assert!(gets <= 2, "{} lookups", gets);
+
+        // with the store down, new credentials are fetched once and kept locally as well
+        store.down.store(true, Ordering::SeqCst);
+        for _ in 0..5 {
+            let token = cache.get("key2", fetcher(fetches.clone())).await;
+            assert_eq!(token.as_deref(), Some("token1"));
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_hanging_store_falls_back_to_the_local_tier() {
+        let store = Arc::new(FlakyStore::default());
+        let cache = SecretsCache::with_store(store.clone());
+        cache
+            .insert("key1".to_string(), "token1".to_string(), expiring_in(3600))
+            .await;
+        store.hanging.store(true, Ordering::SeqCst);
+
+        let started = Instant::now();
+        assert_eq!(cache.peek("key1").await.unwrap().get_value(), "token1");
+        // a missing token is fetched without the lock the store does not hand out
+        let fetcher = || async {
+            Ok::<_, TokenError>(SecretValue::new("token2".to_string(), expiring_in(3600)))
+        };
+        assert_eq!(cache.get("key2", fetcher).await.as_deref(), Some("token2"));
+        assert!(started.elapsed() < 5 * STORE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_rejected() {
+        let cache = SecretsCache::new();
+        cache
+            .insert("key1".to_string(), "token1".to_string(), expiring_in(3600))
+            .await;
+        cache
+            .insert("key2".to_string(), "token2".to_string(), expiring_in(3600))
+            .await;
+
+        // a token that has been replaced already is kept
+        assert!(cache.invalidate_rejected("key2", "old").await);
+        assert!(cache.peek("key2").await.is_some());
+
+        assert!(cache.invalidate_rejected("key1", "token1").await);
+        assert!(cache.peek("key1").await.is_none());
+
+        // its successor is not dropped right away
+        cache
+            .insert("key1".to_string(), "token1b".to_string(), expiring_in(3600))
+            .await;
+        assert!(!cache.invalidate_rejected("key1", "token1b").await);
+        assert!(cache.peek("key1").await.is_some());
+    }
+
+    #[test]
+    fn test_credential_key() {
+        assert_eq!(credential_key("key1"), credential_key("key1"));
+        assert_ne!(credential_key("key1"), credential_key("key2"));
+        assert!(!credential_key("key1").contains("key1"));
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_credential() {
+        let cache = SecretsCache::new();
+        cache
+            .insert(
+                credential_key("key1"),
+                "token1".to_string(),
+                expiring_in(3600),
+            )
+            .await;
+        cache
+            .insert(
+                credential_key("key2"),
+                "token2".to_string(),
+                expiring_in(3600),
+            )
+            .await;
+
+        cache.invalidate_credential("key1").await;
+        assert!(cache.peek(&credential_key("key1")).await.is_none());
+        assert!(cache.peek(&credential_key("key2")).await.is_some());
+    }
+
     async fn get_bearer_with_url(
         api_key: String,
         base_url: &str,
@@ -237,7 +952,7 @@ mod tests {
             ("apikey", &api_key),
         ];
         let resp = client
-            .post(&format!("{}/identity/token", base_url))
+            .post(format!("{}/identity/token", base_url))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .form(&params)
             .send()
@@ -251,4 +966,29 @@ mod tests {
             Err(format!("Failed to get token: {}", err_text).into())
         }
     }
+
+    #[tokio::test]
+    async fn test_flush_on_shutdown_writes_the_token_file() {
+        use crate::credentials::token_file::EncryptedTokenFile;
+
+        let path = std::env::temp_dir().join(format!("osp-{}-flush", std::process::id()));
+        let key = [9; 32];
+        let store = MemoryTokenStore::with_file(EncryptedTokenFile::new(&path, key));
+        let cache = SecretsCache::with_store(Arc::new(store));
+        cache
+            .insert("key1".to_string(), "token1".to_string(), now_secs() + 3600)
+            .await;
+
+        let (shutdown, watch) = tokio::sync::watch::channel(false);
+        let flusher = TokenStoreFlusher::new(cache.clone());
+        let flushing = tokio::spawn(async move { flusher.start(watch).await });
+        shutdown.send(true).unwrap();
+        flushing.await.unwrap();
+
+        // written while the store is still alive, as on exit it is not dropped
+        let tokens = EncryptedTokenFile::new(&path, key).load().unwrap();
+        assert_eq!(tokens["key1"].get_value(), "token1");
+        drop(cache);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,551 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use http::{HeaderMap, Uri};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use pingora::http::RequestHeader;
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+pub const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+pub const STREAMING_PAYLOAD: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD";
+pub const STREAMING_PAYLOAD_TRAILER: &str = "STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER";
+pub const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// Characters left untouched by the AWS flavour of URI encoding
+const AWS_URI_ENCODE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn uri_encode(input: &str) -> String {
+    utf8_percent_encode(input, AWS_URI_ENCODE).to_string()
+}
+
+fn uri_decode(input: &str) -> String {
+    percent_decode_str(input).decode_utf8_lossy().into_owned()
+}
+
+/// Derives the SigV4 signing key for the given credential scope.
+pub fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let k_date = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    let k_region = hmac_sha256(&k_date, region.as_bytes());
+    let k_service = hmac_sha256(&k_region, service.as_bytes());
+    hmac_sha256(&k_service, b"aws4_request")
+}
+
+/// Normalizes a (possibly already encoded) path into its canonical form.
+/// S3 does not normalize paths, so empty segments are preserved.
+pub fn canonical_uri(path: &str) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    path.split('/')
+        .map(|segment| uri_encode(&uri_decode(segment)))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Sorts and re-encodes the query parameters, leaving out any name in `exclude`.
+pub fn canonical_query_string(query: &str, exclude: &[&str]) -> String {
+    let mut params: Vec<(String, String)> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) => (uri_decode(name), uri_decode(value)),
+            None => (uri_decode(pair), String::new()),
+        })
+        .filter(|(name, _)| !exclude.contains(&name.as_str()))
+        .map(|(name, value)| (uri_encode(&name), uri_encode(&value)))
+        .collect();
+    params.sort();
+
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Renders the canonical headers block for the given (lowercase) signed header names.
+/// Returns the name of the first signed header missing from the request as error.
+pub fn canonical_headers(headers: &HeaderMap, signed_headers: &[&str]) -> Result<String, String> {
+    let mut canonical = String::new();
+    for name in signed_headers {
+        let values: Vec<String> = headers
+            .get_all(*name)
+            .iter()
+            .map(|v| {
+                String::from_utf8_lossy(v.as_bytes())
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect();
+        if values.is_empty() {
+            return Err(name.to_string());
+        }
+        canonical.push_str(&format!("{}:{}\n", name, values.join(",")));
+    }
+    Ok(canonical)
+}
+
+pub fn canonical_request(
+    method: &str,
+    canonical_uri: &str,
+    canonical_query: &str,
+    canonical_headers: &str,
+    signed_headers: &str,
+    payload_hash: &str,
+) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method, canonical_uri, canonical_query, canonical_headers, signed_headers, payload_hash
+    )
+}
+
+pub fn string_to_sign(amz_date: &str, credential_scope: &str, canonical_request: &str) -> String {
+    format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        amz_date,
+        credential_scope,
+        sha256_hex(canonical_request.as_bytes())
+    )
+}
+
+pub fn signature(signing_key: &[u8], string_to_sign: &str) -> String {
+    hex::encode(hmac_sha256(signing_key, string_to_sign.as_bytes()))
+}
+
+/// Signs an upstream request in place with the given HMAC credentials: sets `x-amz-date`,
+/// makes sure `x-amz-content-sha256` is present and replaces the `Authorization` header.
+/// The host, content headers and every `x-amz-*` header end up in the signature.
+pub fn sign_request(
+    request: &mut RequestHeader,
+    access_key: &str,
+    secret_key: &str,
+    region: &str,
+    now: DateTime<Utc>,
+) -> pingora::Result<()> {
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = &amz_date[..8];
+
+    request.remove_header("authorization");
+    request.remove_header("x-amz-security-token");
+    request.insert_header("x-amz-date", &amz_date)?;
+    let payload_hash = match request.headers.get("x-amz-content-sha256") {
+        Some(value) => value.to_str().unwrap_or(UNSIGNED_PAYLOAD).to_string(),
+        None => {
+            request.insert_header("x-amz-content-sha256", UNSIGNED_PAYLOAD)?;
+            UNSIGNED_PAYLOAD.to_string()
+        }
+    };
+
+    let mut signed_header_names: Vec<&str> = request
+        .headers
+        .keys()
+        .map(|name| name.as_str())
+        .filter(|name| {
+            matches!(*name, "host" | "content-md5" | "content-type") || name.starts_with("x-amz-")
+        })
+        .collect();
+    signed_header_names.sort_unstable();
+    signed_header_names.dedup();
+    let signed_headers = signed_header_names.join(";");
+
+    let canonical_headers = canonical_headers(&request.headers, &signed_header_names)
+        .map_err(|_| pingora::Error::new_str("Failed to build canonical headers"))?;
+    let canonical_request = canonical_request(
+        request.method.as_str(),
+        &canonical_uri(request.uri.path()),
+        &canonical_query_string(request.uri.query().unwrap_or(""), &[]),
+        &canonical_headers,
+        &signed_headers,
+        &payload_hash,
+    );
+
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let key = signing_key(secret_key, date, region, "s3");
+    let signature = signature(&key, &string_to_sign(&amz_date, &scope, &canonical_request));
+
+    request.insert_header(
+        "authorization",
+        format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, access_key, scope, signed_headers, signature
+        ),
+    )?;
+    Ok(())
+}
+
+/// Builds a presigned URL (SigV4 query-string authentication) for `path` on the server at
+/// `base_url`, e.g. `http://localhost:6190`. Only the host header is signed.
+#[allow(clippy::too_many_arguments)]
+pub fn presign_url(
+    base_url: &str,
+    path: &str,
+    method: &str,
+    access_key: &str,
+    secret_key: &str,
+    region: &str,
+    expires: u64,
+    now: DateTime<Utc>,
+) -> Result<String, String> {
+    let base_uri = base_url
+        .trim_end_matches('/')
+        .parse::<Uri>()
+        .map_err(|e| format!("Invalid base url {}: {}", base_url, e))?;
+    let authority = base_uri
+        .authority()
+        .ok_or_else(|| format!("Base url {} has no host", base_url))?;
+    let scheme = base_uri.scheme_str().unwrap_or("http");
+
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let query = format!(
+        "X-Amz-Algorithm={}&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+        ALGORITHM,
+        uri_encode(&format!("{}/{}", access_key, scope)),
+        amz_date,
+        expires
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "host",
+        authority
+            .as_str()
+            .parse()
+            .map_err(|_| format!("Invalid host {}", authority))?,
+    );
+    let uri_path = canonical_uri(path);
+    let canonical_request = canonical_request(
+        &method.to_uppercase(),
+        &uri_path,
+        &canonical_query_string(&query, &[]),
+        &canonical_headers(&headers, &["host"])?,
+        "host",
+        UNSIGNED_PAYLOAD,
+    );
+    let key = signing_key(secret_key, date, region, "s3");
+    let signature = signature(&key, &string_to_sign(&amz_date, &scope, &canonical_request));
+
+    Ok(format!(
+        "{}://{}{}?{}&X-Amz-Signature={}",
+        scheme, authority, uri_path, query, signature
+    ))
+}
+
+/// Computes the chained signatures of an `aws-chunked` payload, seeded with the
+/// signature of the request headers.
+#[derive(Debug, Clone)]
+pub struct ChunkSigner {
+    signing_key: Vec<u8>,
+    amz_date: String,
+    credential_scope: String,
+    previous_signature: String,
+}
+
+impl ChunkSigner {
+    pub fn new(
+        signing_key: Vec<u8>,
+        amz_date: &str,
+        credential_scope: &str,
+        seed_signature: &str,
+    ) -> Self {
+        ChunkSigner {
+            signing_key,
+            amz_date: amz_date.to_string(),
+            credential_scope: credential_scope.to_string(),
+            previous_signature: seed_signature.to_string(),
+        }
+    }
+
+    /// Signature of the next chunk, given the hex sha256 of its data
+    pub fn next_signature(&mut self, chunk_hash: &str) -> String {
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256-PAYLOAD\n{}\n{}\n{}\n{}\n{}",
+            self.amz_date, self.credential_scope, self.previous_signature, EMPTY_SHA256, chunk_hash
+        );
+        self.previous_signature = signature(&self.signing_key, &string_to_sign);
+        self.previous_signature.clone()
+    }
+
+    /// Signature of the trailing headers, given the hex sha256 of their canonical form
+    pub fn trailer_signature(&mut self, trailer_hash: &str) -> String {
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256-TRAILER\n{}\n{}\n{}\n{}",
+            self.amz_date, self.credential_scope, self.previous_signature, trailer_hash
+        );
+        self.previous_signature = signature(&self.signing_key, &string_to_sign);
+        self.previous_signature.clone()
+    }
+}
+
+/// Hashes a request body to check it against its `x-amz-content-sha256` header. The
+/// signature covers the header only, so without this a signed request could be replayed
+/// with another body.
+#[derive(Debug, Clone)]
+pub struct PayloadVerifier {
+    expected: String,
+    hasher: Sha256,
+}
+
+impl PayloadVerifier {
+    /// `None` when the header is not a hash of the whole body, e.g. `UNSIGNED-PAYLOAD` or
+    /// a streaming upload, whose chunks are verified one by one instead.
+    pub fn new(content_sha256: &str) -> Option<Self> {
+        (content_sha256.len() == 64 && content_sha256.bytes().all(|b| b.is_ascii_hexdigit())).then(
+            || PayloadVerifier {
+                expected: content_sha256.to_ascii_lowercase(),
+                hasher: Sha256::new(),
+            },
+        )
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Whether the body seen matches the header.
+    pub fn verify(self) -> bool {
+        hex::encode(self.hasher.finalize()) == self.expected
+    }
+}
+
+/// Compares two hex signatures without short-circuiting on the first difference.
+pub fn signatures_match(expected: &str, provided: &str) -> bool {
+    let expected = expected.as_bytes();
+    let provided = provided.as_bytes();
+    if expected.len() != provided.len() {
+        return false;
+    }
+    expected
+        .iter()
+        .zip(provided)
+        .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    // Example from the AWS S3 SigV4 documentation ("GET Object").
+    const SECRET: &str = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+
+    #[test]
+    fn test_canonical_uri_encodes_segments() {
+        assert_eq!(canonical_uri("/test.txt"), "/test.txt");
+        assert_eq!(canonical_uri("/my folder/a+b"), "/my%20folder/a%2Bb");
+        assert_eq!(canonical_uri("/already%20encoded"), "/already%20encoded");
+        assert_eq!(canonical_uri("/some//path"), "/some//path");
+        assert_eq!(canonical_uri(""), "/");
+    }
+
+    #[test]
+    fn test_canonical_query_string_sorts_and_encodes() {
+        assert_eq!(
+            canonical_query_string("prefix=a b&max-keys=20&list-type=2", &[]),
+            "list-type=2&max-keys=20&prefix=a%20b"
+        );
+        assert_eq!(canonical_query_string("acl", &[]), "acl=");
+        assert_eq!(
+            canonical_query_string("X-Amz-Signature=abc&uploads", &["X-Amz-Signature"]),
+            "uploads="
+        );
+    }
+
+    #[test]
+    fn test_canonical_headers_missing_header() {
+        let headers = HeaderMap::new();
+        assert_eq!(
+            canonical_headers(&headers, &["host"]),
+            Err("host".to_string())
+        );
+    }
+
+    #[test]
+    fn test_aws_get_object_example() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "host",
+            HeaderValue::from_static("examplebucket.s3.amazonaws.com"),
+        );
+        headers.insert("range", HeaderValue::from_static("bytes=0-9"));
+        headers.insert(
+            "x-amz-content-sha256",
+            HeaderValue::from_static(EMPTY_SHA256),
+        );
+        headers.insert("x-amz-date", HeaderValue::from_static("20130524T000000Z"));
+
+        let signed = ["host", "range", "x-amz-content-sha256", "x-amz-date"];
+        let creq = canonical_request(
+            "GET",
+            &canonical_uri("/test.txt"),
+            &canonical_query_string("", &[]),
+            &canonical_headers(&headers, &signed).unwrap(),
+            &signed.join(";"),
+            EMPTY_SHA256,
+        );
+        let sts = string_to_sign(
+            "20130524T000000Z",
+            "20130524/us-east-1/s3/aws4_request",
+            &creq,
+        );
+        let key = signing_key(SECRET, "20130524", "us-east-1", "s3");
+
+        assert_eq!(
+            signature(&key, &sts),
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+    }
+
+    #[test]
+    fn test_sign_request_roundtrip() {
+        use crate::utils::validator::verify_signature;
+        use std::collections::HashMap;
+
+        let mut req = RequestHeader::build("PUT", b"/some/key.txt?tagging", None).unwrap();
+        req.insert_header(
+            "host",
+            "bucket01.s3.eu-de.cloud-object-storage.appdomain.cloud",
+        )
+        .unwrap();
+        req.insert_header("authorization", "Bearer to-be-replaced")
+            .unwrap();
+        req.insert_header("content-type", "text/plain").unwrap();
+        req.insert_header("x-amz-meta-owner", "proxy").unwrap();
+
+        sign_request(&mut req, "AKID", SECRET, "eu-de", Utc::now()).unwrap();
+
+        let authorization = req.headers.get("authorization").unwrap().to_str().unwrap();
+        assert!(authorization.contains("/eu-de/s3/aws4_request"));
+        assert!(authorization.contains(
+            "SignedHeaders=content-type;host;x-amz-content-sha256;x-amz-date;x-amz-meta-owner,"
+        ));
+        assert_eq!(
+            req.headers.get("x-amz-content-sha256").unwrap(),
+            UNSIGNED_PAYLOAD
+        );
+
+        let keystore = HashMap::from([("AKID".to_string(), SECRET.to_string())]);
+        assert_eq!(verify_signature(&req, &keystore), Ok("AKID".to_string()));
+    }
+
+    #[test]
+    fn test_presign_url_roundtrip() {
+        use crate::utils::validator::verify_signature;
+        use std::collections::HashMap;
+
+        let url = presign_url(
+            "http://localhost:6190/",
+            "/bucket01/my folder/report.csv",
+            "get",
+            "AKID",
+            SECRET,
+            "us-east-1",
+            600,
+            Utc::now(),
+        )
+        .unwrap();
+        assert!(
+            url.starts_with(
+                "http://localhost:6190/bucket01/my%20folder/report.csv?X-Amz-Algorithm="
+            )
+        );
+
+        let path_and_query = url.trim_start_matches("http://localhost:6190");
+        let mut req = RequestHeader::build("GET", path_and_query.as_bytes(), None).unwrap();
+        req.insert_header("host", "localhost:6190").unwrap();
+
+        let keystore = HashMap::from([("AKID".to_string(), SECRET.to_string())]);
+        assert_eq!(verify_signature(&req, &keystore), Ok("AKID".to_string()));
+
+        let mut put = RequestHeader::build("PUT", path_and_query.as_bytes(), None).unwrap();
+        put.insert_header("host", "localhost:6190").unwrap();
+        assert!(verify_signature(&put, &keystore).is_err());
+    }
+
+    #[test]
+    fn test_presign_url_invalid_base_url() {
+        assert!(
+            presign_url(
+                "/no-host",
+                "/b/k",
+                "GET",
+                "AK",
+                SECRET,
+                "us-east-1",
+                60,
+                Utc::now()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_chunk_signer_aws_example() {
+        // Example from the AWS S3 "Signature Calculations for the Authorization Header:
+        // Transferring Payload in Multiple Chunks" documentation.
+        let key = signing_key(SECRET, "20130524", "us-east-1", "s3");
+        let mut signer = ChunkSigner::new(
+            key,
+            "20130524T000000Z",
+            "20130524/us-east-1/s3/aws4_request",
+            "4f232c4386841ef735655705268965c44a0e4690baa4adea153f7db9fa80a0a9",
+        );
+
+        assert_eq!(
+            signer.next_signature(&sha256_hex(&[b'a'; 65536])),
+            "ad80c730a21e5b8d04586a2213dd63b9a0e99e0e2307b0ade35a65485a288648"
+        );
+        assert_eq!(
+            signer.next_signature(&sha256_hex(&[b'a'; 1024])),
+            "0055627c9e194cb4542bae2aa5492e3c1575bbb81b612b7d234b86a503ef5497"
+        );
+        assert_eq!(
+            signer.next_signature(EMPTY_SHA256),
+            "b6c6ea8a5354eaf15b3cb7646744f4275b71ea724fed81ceb9323e279d449df9"
+        );
+    }
+
+    #[test]
+    fn test_signatures_match() {
+        assert!(signatures_match("abcd", "abcd"));
+        assert!(!signatures_match("abcd", "abce"));
+        assert!(!signatures_match("abcd", "abc"));
+    }
+
+    #[test]
+    fn test_payload_verifier() {
+        assert!(PayloadVerifier::new(UNSIGNED_PAYLOAD).is_none());
+        assert!(PayloadVerifier::new(STREAMING_PAYLOAD).is_none());
+        assert!(PayloadVerifier::new(EMPTY_SHA256).unwrap().verify());
+
+        let expected = sha256_hex(b"hello world").to_ascii_uppercase();
+        let mut verifier = PayloadVerifier::new(&expected).unwrap();
+        verifier.update(b"hello ");
+        verifier.update(b"world");
+        assert!(verifier.verify());
+
+        let mut verifier = PayloadVerifier::new(&expected).unwrap();
+        verifier.update(b"hello there");
+        assert!(!verifier.verify());
+    }
+}
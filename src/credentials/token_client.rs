@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use reqwest::{Client, StatusCode};
+use tracing::{error, warn};
+
+use crate::credentials::token_provider::TokenError;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Attempts per token request, the first one included.
+const MAX_ATTEMPTS: u32 = 3;
+/// Wait before the first retry, doubled for every further one.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+/// Consecutive failed requests after which a token endpoint is considered down.
+const FAILURE_THRESHOLD: u32 = 5;
+/// How long requests to an endpoint that is down fail without being sent.
+const OPEN_DURATION: Duration = Duration::from_secs(30);
+
+static SHARED: OnceLock<TokenClient> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct Breaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Fails requests to a token endpoint fast for a while once it keeps failing, instead of
+/// letting every request wait for its timeouts and retries.
+#[derive(Debug)]
+struct CircuitBreaker {
+    threshold: u32,
+    open_duration: Duration,
+    endpoints: Mutex<HashMap<String, Breaker>>,
+}
+
+impl CircuitBreaker {
+    fn is_open(&self, url: &str, now: Instant) -> bool {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .get(url)
+            .and_then(|breaker| breaker.open_until)
+            .is_some_and(|open_until| open_until > now)
+    }
+
+    fn record(&self, url: &str, success: bool, now: Instant) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        if success {
+            endpoints.remove(url);
+            return;
+        }
+        let breaker = endpoints.entry(url.to_string()).or_default();
+        breaker.consecutive_failures += 1;
+        if breaker.consecutive_failures >= self.threshold {
+            warn!(
+                "Token endpoint {} failed {} times in a row, pausing requests for {:?}",
+                url, breaker.consecutive_failures, self.open_duration
+            );
+            breaker.open_until = Some(now + self.open_duration);
+        }
+    }
+}
+
+/// What a single attempt at a token request came to.
+enum Attempt {
+    Success(String),
+    /// The endpoint answered, but refused; retrying will not help
+    Rejected(StatusCode, String),
+    /// A network error, timeout or server error; worth retrying
+    Failed(String),
+}
+
+/// The HTTP client token providers post their requests with: one connection pool for the
+/// whole proxy, connect and read timeouts, retries with exponential backoff on network and
+/// server errors, and a circuit breaker per token endpoint.
+#[derive(Debug)]
+pub struct TokenClient {
+    client: Client,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    breaker: CircuitBreaker,
+}
+
+impl TokenClient {
+    pub fn new(
+        max_attempts: u32,
+        initial_backoff: Duration,
+        failure_threshold: u32,
+        open_duration: Duration,
+    ) -> Self {
+        let client = Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .read_timeout(READ_TIMEOUT)
+            .build()
+            .expect("the token client configuration is valid");
+        TokenClient {
+            client,
+            max_attempts: max_attempts.max(1),
+            initial_backoff,
+            breaker: CircuitBreaker {
+                threshold: failure_threshold,
+                open_duration,
+                endpoints: Mutex::new(HashMap::new()),
+            },
+        }
+    }
+
+    /// The client shared by all token providers.
+    pub fn shared() -> &'static TokenClient {
+        SHARED.get_or_init(|| {
+            TokenClient::new(
+                MAX_ATTEMPTS,
+                INITIAL_BACKOFF,
+                FAILURE_THRESHOLD,
+                OPEN_DURATION,
+            )
+        })
+    }
+
+    /// Posts `form` to `url`, with HTTP basic authentication when `basic_auth` is given,
+    /// and returns the body of the successful response.
+    pub async fn post_form(
+        &self,
+        url: &str,
+        form: &[(&str, &str)],
+        basic_auth: Option<(&str, &str)>,
+    ) -> Result<String, TokenError> {
+        if self.breaker.is_open(url, Instant::now()) {
+            return Err(format!("Token endpoint {} is unavailable", url).into());
+        }
+
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            let outcome = self.attempt(url, form, basic_auth).await;
+            self.breaker
+                .record(url, !matches!(outcome, Attempt::Failed(_)), Instant::now());
+            match outcome {
+                Attempt::Success(body) => return Ok(body),
+                Attempt::Rejected(status, body) => {
+                    error!("Token request to {} was rejected with {}", url, status);
+                    return Err(format!("Failed to get token: {}", body).into());
+                }
+                Attempt::Failed(reason)
+                    if attempt >= self.max_attempts
+                        || self.breaker.is_open(url, Instant::now()) =>
+                {
+                    error!(
+                        "Token request to {} failed after {} attempts: {}",
+                        url, attempt, reason
+                    );
+                    return Err(format!("Failed to get token: {}", reason).into());
+                }
+                Attempt::Failed(reason) => {
+                    warn!(
+                        "Token request to {} failed, retrying in {:?}: {}",
+                        url, backoff, reason
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn attempt(
+        &self,
+        url: &str,
+        form: &[(&str, &str)],
+        basic_auth: Option<(&str, &str)>,
+    ) -> Attempt {
+        let mut request = self.client.post(url).form(form);
+        if let Some((username, password)) = basic_auth {
+            request = request.basic_auth(username, Some(password));
+        }
+
+        let resp = match request.send().await {
+            Ok(resp) => resp,
+            Err(e) => return Attempt::Failed(e.to_string()),
+        };
+        let status = resp.status();
+        let body = match resp.text().await {
+            Ok(body) => body,
+            Err(e) => return Attempt::Failed(e.to_string()),
+        };
+
+        if status.is_success() {
+            Attempt::Success(body)
+        } else if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Attempt::Failed(format!("{}: {}", status, body))
+        } else {
+            Attempt::Rejected(status, body)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn client() -> TokenClient {
+        TokenClient::new(3, Duration::from_millis(1), 5, Duration::from_secs(30))
+    }
+
+    #[tokio::test]
+    async fn test_retries_server_errors() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("token"))
+            .mount(&mock_server)
+            .await;
+
+        let body = client()
+            .post_form(&mock_server.uri(), &[("grant_type", "x")], None)
+            .await
+            .unwrap();
+        assert_eq!(body, "token");
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_does_not_retry_rejections() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("Invalid API key"))
+            .mount(&mock_server)
+            .await;
+
+        let error = client()
+            .post_form(&mock_server.uri(), &[], None)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Failed to get token: Invalid API key");
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_fails_fast() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&mock_server)
+            .await;
+
+        let client = client();
+        // 3 attempts each, the breaker opens on the 5th failure
+        assert!(
+            client
+                .post_form(&mock_server.uri(), &[], None)
+                .await
+                .is_err()
+        );
+        assert!(
+            client
+                .post_form(&mock_server.uri(), &[], None)
+                .await
+                .is_err()
+        );
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 5);
+
+        let error = client
+            .post_form(&mock_server.uri(), &[], None)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("is unavailable"));
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_circuit_breaker_closes_again() {
+        let breaker = CircuitBreaker {
+            threshold: 2,
+            open_duration: Duration::from_secs(30),
+            endpoints: Mutex::new(HashMap::new()),
+        };
+        let now = Instant::now();
+        breaker.record("iam", false, now);
+        assert!(!breaker.is_open("iam", now));
+        breaker.record("iam", false, now);
+        assert!(breaker.is_open("iam", now));
+        assert!(!breaker.is_open("other", now));
+        assert!(!breaker.is_open("iam", now + Duration::from_secs(31)));
+
+        breaker.record("iam", true, now);
+        breaker.record("iam", false, now);
+        assert!(!breaker.is_open("iam", now));
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use openssl::rand::rand_bytes;
+use openssl::symm::{Cipher, decrypt_aead, encrypt_aead};
+
+use crate::credentials::secrets_proxy::SecretValue;
+
+/// Identifies the file format, and is authenticated along with the tokens.
+const MAGIC: &[u8] = b"OSPTOKENS1";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+pub(crate) const KEY_LEN: usize = 32;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Encrypts `plaintext` with AES-256-GCM under `key`, authenticating `aad` along with it,
+/// and returns the nonce, the tag and the ciphertext.
+pub(crate) fn seal(key: &[u8; KEY_LEN], aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand_bytes(&mut nonce).map_err(io::Error::other)?;
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        aad,
+        plaintext,
+        &mut tag,
+    )
+    .map_err(io::Error::other)?;
+    Ok([&nonce[..], &tag, &ciphertext].concat())
+}
+
+/// Decrypts what `seal` returned for the same `key` and `aad`.
+pub(crate) fn open(key: &[u8; KEY_LEN], aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(invalid_data("encrypted tokens are truncated"));
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (tag, ciphertext) = rest.split_at(TAG_LEN);
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        aad,
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| invalid_data("tokens cannot be decrypted with this key"))
+}
+
+/// Parses the hex encoded 32 byte AES key of a token cache file, e.g. the output of
+/// `openssl rand -hex 32`. The key is used as is, so it has to be random rather than a
+/// passphrase.
+pub fn parse_key(hex_key: &str) -> Result<[u8; KEY_LEN], String> {
+    let key = hex::decode(hex_key.trim())
+        .map_err(|_| "token_cache_key must be hex encoded".to_string())?;
+    key.try_into().map_err(|key: Vec<u8>| {
+        format!(
+            "token_cache_key must be {} random bytes, got {}",
+            KEY_LEN,
+            key.len()
+        )
+    })
+}
+
+/// Keeps the token cache on disk, encrypted with AES-256-GCM under a locally configured
+/// key, so a restarted proxy does not have to fetch every token again.
+pub struct EncryptedTokenFile {
+    path: PathBuf,
+    key: [u8; KEY_LEN],
+}
+
+impl std::fmt::Debug for EncryptedTokenFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedTokenFile")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedTokenFile {
+    pub fn new(path: impl AsRef<Path>, key: [u8; KEY_LEN]) -> Self {
+        EncryptedTokenFile {
+            path: path.as_ref().to_path_buf(),
+            key,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The tokens in the file that have not expired yet; none when there is no file.
+    pub fn load(&self) -> io::Result<HashMap<String, SecretValue>> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+
+        let sealed = data
+            .strip_prefix(MAGIC)
+            .ok_or_else(|| invalid_data("not a token cache file"))?;
+        let plaintext = open(&self.key, MAGIC, sealed)?;
+
+        let mut tokens: HashMap<String, SecretValue> = serde_json::from_slice(&plaintext)?;
+        tokens.retain(|_, secret| !secret.expires_within(0));
+        Ok(tokens)
+    }
+
+    /// Replaces the file with `tokens`, atomically and readable by the owner only.
+    pub fn save(&self, tokens: &HashMap<String, SecretValue>) -> io::Result<()> {
+        let sealed = seal(&self.key, MAGIC, &serde_json::to_vec(tokens)?)?;
+
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&temp_path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&sealed)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::secrets_proxy::now_secs;
+
+    const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("osp-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_round_trip_drops_expired_tokens() {
+        let path = temp_path("round-trip");
+        let file = EncryptedTokenFile::new(&path, KEY);
+        let tokens = HashMap::from([
+            (
+                "apikey:1".to_string(),
+                SecretValue::new("token1".to_string(), now_secs() + 3600),
+            ),
+            (
+                "apikey:2".to_string(),
+                SecretValue::new("token2".to_string(), now_secs() - 1),
+            ),
+        ]);
+        file.save(&tokens).unwrap();
+
+        let raw = fs::read(&path).unwrap();
+        assert!(!raw.windows(6).any(|w| w == b"token1"));
+
+        let loaded = file.load().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded["apikey:1"].get_value(), "token1");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_wrong_key_or_tampering_is_rejected() {
+        let path = temp_path("wrong-key");
+        let tokens = HashMap::from([(
+            "apikey:1".to_string(),
+            SecretValue::new("token1".to_string(), now_secs() + 3600),
+        )]);
+        EncryptedTokenFile::new(&path, KEY).save(&tokens).unwrap();
+
+        assert!(EncryptedTokenFile::new(&path, [8; KEY_LEN]).load().is_err());
+
+        let mut raw = fs::read(&path).unwrap();
+        *raw.last_mut().unwrap() ^= 1;
+        fs::write(&path, raw).unwrap();
+        assert!(EncryptedTokenFile::new(&path, KEY).load().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sealed_data_is_bound_to_key_and_aad() {
+        let sealed = seal(&KEY, b"token:1", b"token1").unwrap();
+        assert_eq!(open(&KEY, b"token:1", &sealed).unwrap(), b"token1");
+        assert!(open(&KEY, b"token:2", &sealed).is_err());
+        assert!(open(&[8; KEY_LEN], b"token:1", &sealed).is_err());
+        assert!(open(&KEY, b"token:1", &sealed[..NONCE_LEN]).is_err());
+    }
+
+    #[test]
+    fn test_missing_file_is_empty() {
+        let file = EncryptedTokenFile::new(temp_path("missing"), KEY);
+        assert!(file.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = parse_key(&format!("{}\n", "ab".repeat(KEY_LEN))).unwrap();
+        assert_eq!(key, [0xab; KEY_LEN]);
+        assert!(parse_key("local secret").is_err());
+        assert!(parse_key(&"ab".repeat(16)).is_err());
+        assert!(parse_key("").is_err());
+    }
+}
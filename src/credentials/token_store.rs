@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
+
+use crate::credentials::secrets_proxy::SecretValue;
+use crate::credentials::token_file::EncryptedTokenFile;
+
+pub type StoreError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Where a `SecretsCache` keeps its tokens. Stores shared between proxies also coordinate
+/// who refreshes a token, so that only one of them fetches it.
+#[async_trait]
+pub trait TokenStore: Send + Sync + std::fmt::Debug {
+    async fn get(&self, key: &str) -> Result<Option<SecretValue>, StoreError>;
+
+    async fn put(&self, key: &str, secret: &SecretValue) -> Result<(), StoreError>;
+
+    async fn remove(&self, key: &str) -> Result<(), StoreError>;
+
+    /// Takes the lock on refreshing `key` for `owner`, for at most `ttl`; `false` while
+    /// someone else holds it.
+    async fn try_lock(&self, key: &str, owner: &str, ttl: Duration) -> Result<bool, StoreError>;
+
+    /// Releases the lock on `key`, if `owner` still holds it.
+    async fn unlock(&self, key: &str, owner: &str) -> Result<(), StoreError>;
+
+    /// Waits until the changes made so far are written, for stores that write behind.
+    async fn flush(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
+
+/// Writes the tokens to an `EncryptedTokenFile` on its own thread, so requests never wait
+/// for the disk. Changes made while a write is in progress are coalesced into the next one.
+#[derive(Debug)]
+struct FileWriter {
+    /// `Some` for a flush, answered once the tokens are written
+    changed: Option<Sender<Option<oneshot::Sender<()>>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FileWriter {
+    fn start(file: EncryptedTokenFile, tokens: Arc<RwLock<HashMap<String, SecretValue>>>) -> Self {
+        let (changed, changes) = mpsc::channel::<Option<oneshot::Sender<()>>>();
+        let thread = std::thread::Builder::new()
+            .name("object-storage-proxy-token-file".to_string())
+            .spawn(move || {
+                while let Ok(change) = changes.recv() {
+                    let mut flushes: Vec<_> = change.into_iter().collect();
+                    while let Ok(change) = changes.try_recv() {
+                        flushes.extend(change);
+                    }
+                    let snapshot = tokens.read().unwrap().clone();
+                    if let Err(e) = file.save(&snapshot) {
+                        error!(
+                            "Failed to write token cache file {}: {}",
+                            file.path().display(),
+                            e
+                        );
+                    }
+                    for flushed in flushes {
+                        let _ = flushed.send(());
+                    }
+                }
+            })
+            .expect("failed to start the token cache file writer");
+        FileWriter {
+            changed: Some(changed),
+            thread: Some(thread),
+        }
+    }
+
+    fn notify(&self) {
+        if let Some(changed) = &self.changed {
+            let _ = changed.send(None);
+        }
+    }
+
+    async fn flush(&self) {
+        let (flushed, written) = oneshot::channel();
+        if let Some(changed) = &self.changed
+            && changed.send(Some(flushed)).is_ok()
+        {
+            let _ = written.await;
+        }
+    }
+}
+
+impl Drop for FileWriter {
+    /// Waits for the pending write, so the last changes are not lost on shutdown.
+    fn drop(&mut self) {
+        self.changed.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Keeps tokens in memory, and optionally in an encrypted file so they survive restarts.
+#[derive(Debug, Default)]
+pub struct MemoryTokenStore {
+    tokens: Arc<RwLock<HashMap<String, SecretValue>>>,
+    /// lock holders and when their lock runs out, by key
+    locks: Mutex<HashMap<String, (String, Instant)>>,
+    /// Where changes are written through to, if anywhere
+    file: Option<FileWriter>,
+}
+
+impl MemoryTokenStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A store starting out with the unexpired tokens in `file`, and writing every change
+    /// through to it in the background. An unreadable file is logged and overwritten.
+    pub fn with_file(file: EncryptedTokenFile) -> Self {
+        let tokens = match file.load() {
+            Ok(tokens) => {
+                info!(
+                    "Loaded {} tokens from {}",
+                    tokens.len(),
+                    file.path().display()
+                );
+                tokens
+            }
+            Err(e) => {
+                warn!("Ignoring token cache file {}: {}", file.path().display(), e);
+                HashMap::new()
+            }
+        };
+        let tokens = Arc::new(RwLock::new(tokens));
+        MemoryTokenStore {
+            tokens: tokens.clone(),
+            locks: Mutex::new(HashMap::new()),
+            file: Some(FileWriter::start(file, tokens)),
+        }
+    }
+
+    fn write_through(&self) {
+        if let Some(file) = &self.file {
+            file.notify();
+        }
+    }
+}
+
+#[async_trait]
+impl TokenStore for MemoryTokenStore {
+    async fn get(&self, key: &str) -> Result<Option<SecretValue>, StoreError> {
+        Ok(self.tokens.read().unwrap().get(key).cloned())
+    }
+
+    async fn put(&self, key: &str, secret: &SecretValue) -> Result<(), StoreError> {
+        self.tokens
+            .write()
+            .unwrap()
+            .insert(key.to_string(), secret.clone());
+        self.write_through();
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), StoreError> {
+        let removed = self.tokens.write().unwrap().remove(key);
+        if removed.is_some() {
+            self.write_through();
+        }
+        Ok(())
+    }
+
+    async fn try_lock(&self, key: &str, owner: &str, ttl: Duration) -> Result<bool, StoreError> {
+        let now = Instant::now();
+        let mut locks = self.locks.lock().unwrap();
+        if let Some((holder, until)) = locks.get(key)
+            && holder != owner
+            && *until > now
+        {
+            return Ok(false);
+        }
+        locks.insert(key.to_string(), (owner.to_string(), now + ttl));
+        Ok(true)
+    }
+
+    async fn unlock(&self, key: &str, owner: &str) -> Result<(), StoreError> {
+        let mut locks = self.locks.lock().unwrap();
+        if locks.get(key).is_some_and(|(holder, _)| holder == owner) {
+            locks.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), StoreError> {
+        if let Some(file) = &self.file {
+            file.flush().await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::secrets_proxy::now_secs;
+
+    #[tokio::test]
+    async fn test_locks_exclude_other_owners_until_released_or_expired() {
+        let store = MemoryTokenStore::new();
+        let ttl = Duration::from_secs(30);
+        assert!(store.try_lock("key1", "proxy1", ttl).await.unwrap());
+        assert!(!store.try_lock("key1", "proxy2", ttl).await.unwrap());
+        assert!(store.try_lock("key2", "proxy2", ttl).await.unwrap());
+
+        // only the holder can release it
+        store.unlock("key1", "proxy2").await.unwrap();
+        assert!(!store.try_lock("key1", "proxy2", ttl).await.unwrap());
+        store.unlock("key1", "proxy1").await.unwrap();
+        assert!(store.try_lock("key1", "proxy2", ttl).await.unwrap());
+
+        assert!(
+            store
+                .try_lock("key3", "proxy1", Duration::ZERO)
+                .await
+                .unwrap()
+        );
+        assert!(store.try_lock("key3", "proxy2", ttl).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_file_backed_store_survives_restart() {
+        let path = std::env::temp_dir().join(format!("osp-{}-token-store", std::process::id()));
+        let key = [7; 32];
+        let store = MemoryTokenStore::with_file(EncryptedTokenFile::new(&path, key));
+        let token = |value: &str| SecretValue::new(value.to_string(), now_secs() + 3600);
+        store.put("key1", &token("token1")).await.unwrap();
+        store.put("key2", &token("token2")).await.unwrap();
+        store.remove("key2").await.unwrap();
+        // waits for the background write
+        drop(store);
+
+        let restarted = MemoryTokenStore::with_file(EncryptedTokenFile::new(&path, key));
+        let token1 = restarted.get("key1").await.unwrap().unwrap();
+        assert_eq!(token1.get_value(), "token1");
+        assert!(restarted.get("key2").await.unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,28 +1,33 @@
 #![warn(clippy::all)]
 
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::fmt::time::ChronoLocal;
 
-use parsers::credentials::parse_token_from_header;
 use pyo3::prelude::*;
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::Utc;
 use http::Uri;
 use http::uri::Authority;
+use percent_encoding::percent_decode_str;
 
-use pyo3::types::{PyModule, PyModuleMethods};
+use pyo3::types::{PyDict, PyModule, PyModuleMethods};
 use pyo3::{Bound, PyResult, Python, pyclass, pyfunction, pymodule, wrap_pyfunction};
 use std::collections::HashMap;
 use std::fmt::Debug;
 
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use dotenv::dotenv;
 use pingora::Result;
+use pingora::http::ResponseHeader;
 use pingora::proxy::{ProxyHttp, Session};
 use pingora::server::Server;
+use pingora::services::background::background_service;
 use pingora::upstreams::peer::HttpPeer;
 
 pub mod parsers;
@@ -31,7 +36,35 @@ use parsers::path::parse_path;
 pub mod credentials;
 
 pub mod utils;
-use credentials::secrets_proxy::{SecretsCache, get_bearer};
+use credentials::bucket_creds::BucketCredsFetcher;
+use credentials::redis_store::RedisTokenStore;
+use credentials::secret_source::{SecretFileWatcher, SecretSource};
+use credentials::secrets_proxy::{SecretsCache, TokenRefresher, TokenStoreFlusher};
+use credentials::signer::{
+    PayloadVerifier, STREAMING_PAYLOAD, STREAMING_PAYLOAD_TRAILER, UNSIGNED_PAYLOAD, presign_url,
+    sign_request,
+};
+use credentials::token_file::{self, EncryptedTokenFile};
+use credentials::token_provider::{
+    IbmIamProvider, OAuth2Config, ProviderCache, TokenCallback, TokenProvider,
+};
+use credentials::token_store::MemoryTokenStore;
+use parsers::aws_chunked::AwsChunkedDecoder;
+use parsers::credentials::{is_presigned, parse_presigned_params, strip_presigned_params};
+use utils::bucket_resolver::{BucketResolver, CachedResolver, PythonBucketResolver};
+use utils::decision_cache::{CachingValidator, DecisionCache};
+use utils::operation::S3Operation;
+use utils::policy::{Policy, PolicyEvaluation};
+use utils::python::{CallbackError, CallbackPool};
+use utils::s3_error::{
+    S3Error, error_code, header_error_code, new_request_id, rejects_credentials,
+};
+use utils::validator::{
+    AccessRequest, AccessTable, AllowAllValidator, AuthDecision, FileValidator,
+    MAX_PRESIGNED_EXPIRES_SECS, PRESIGN_ACCESS_KEY, PythonValidator, SignatureError,
+    StaticValidator, Validator, check_authorization_header, check_presigned_expiry,
+    extract_access_key, is_proxy_presigned, streaming_chunk_signer, verify_signature,
+};
 
 static REQ_COUNTER: Mutex<usize> = Mutex::new(0);
 
@@ -50,6 +83,60 @@ pub struct ProxyServerConfig {
 
     #[pyo3(get, set)]
     pub validator: Option<Py<PyAny>>,
+
+    #[pyo3(get, set)]
+    pub hmac_keystore: Option<PyObject>,
+
+    #[pyo3(get, set)]
+    pub presign_secret: Option<String>,
+
+    #[pyo3(get, set)]
+    pub access_table: Option<PyObject>,
+
+    #[pyo3(get, set)]
+    pub access_table_file: Option<String>,
+
+    #[pyo3(get, set)]
+    pub policy: Option<PyObject>,
+
+    #[pyo3(get, set)]
+    pub policy_file: Option<String>,
+
+    #[pyo3(get, set)]
+    pub callback_workers: usize,
+
+    #[pyo3(get, set)]
+    pub callback_queue_limit: usize,
+
+    #[pyo3(get, set)]
+    pub callback_timeout: f64,
+
+    #[pyo3(get, set)]
+    pub decision_cache: Option<Py<DecisionCache>>,
+
+    #[pyo3(get, set)]
+    pub bucket_resolver: Option<PyObject>,
+
+    #[pyo3(get, set)]
+    pub bucket_resolver_ttl: f64,
+
+    #[pyo3(get, set)]
+    pub bucket_resolver_negative_ttl: f64,
+
+    #[pyo3(get, set)]
+    pub bucket_creds_ttl: f64,
+
+    #[pyo3(get, set)]
+    pub bucket_creds_negative_ttl: f64,
+
+    #[pyo3(get, set)]
+    pub token_cache_file: Option<String>,
+
+    #[pyo3(get, set)]
+    pub token_cache_key: Option<String>,
+
+    #[pyo3(get, set)]
+    pub redis_url: Option<String>,
 }
 
 impl Default for ProxyServerConfig {
@@ -59,6 +146,24 @@ impl Default for ProxyServerConfig {
             cos_map: Python::with_gil(|py| py.None()),
             port: 6190,
             validator: None,
+            hmac_keystore: None,
+            presign_secret: None,
+            access_table: None,
+            access_table_file: None,
+            policy: None,
+            policy_file: None,
+            callback_workers: 8,
+            callback_queue_limit: 64,
+            callback_timeout: 5.0,
+            decision_cache: None,
+            bucket_resolver: None,
+            bucket_resolver_ttl: 300.0,
+            bucket_resolver_negative_ttl: 30.0,
+            bucket_creds_ttl: 300.0,
+            bucket_creds_negative_ttl: 30.0,
+            token_cache_file: None,
+            token_cache_key: None,
+            redis_url: None,
         }
     }
 }
@@ -66,42 +171,312 @@ impl Default for ProxyServerConfig {
 #[pymethods]
 impl ProxyServerConfig {
     #[new]
+    #[pyo3(signature = (
+        bucket_creds_fetcher,
+        cos_map,
+        port,
+        validator,
+        hmac_keystore=None,
+        presign_secret=None,
+        access_table=None,
+        access_table_file=None,
+        policy=None,
+        policy_file=None,
+        callback_workers=8,
+        callback_queue_limit=64,
+        callback_timeout=5.0,
+        decision_cache=None,
+        bucket_resolver=None,
+        bucket_resolver_ttl=300.0,
+        bucket_resolver_negative_ttl=30.0,
+        bucket_creds_ttl=300.0,
+        bucket_creds_negative_ttl=30.0,
+        token_cache_file=None,
+        token_cache_key=None,
+        redis_url=None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         bucket_creds_fetcher: Option<PyObject>,
         cos_map: PyObject,
         port: u16,
         validator: Option<PyObject>,
+        hmac_keystore: Option<PyObject>,
+        presign_secret: Option<String>,
+        access_table: Option<PyObject>,
+        access_table_file: Option<String>,
+        policy: Option<PyObject>,
+        policy_file: Option<String>,
+        callback_workers: usize,
+        callback_queue_limit: usize,
+        callback_timeout: f64,
+        decision_cache: Option<Py<DecisionCache>>,
+        bucket_resolver: Option<PyObject>,
+        bucket_resolver_ttl: f64,
+        bucket_resolver_negative_ttl: f64,
+        bucket_creds_ttl: f64,
+        bucket_creds_negative_ttl: f64,
+        token_cache_file: Option<String>,
+        token_cache_key: Option<String>,
+        redis_url: Option<String>,
     ) -> Self {
         ProxyServerConfig {
-            bucket_creds_fetcher: bucket_creds_fetcher.map(|obj| obj.into()),
+            bucket_creds_fetcher,
             cos_map,
             port,
-            validator: validator.map(|obj| obj.into()),
+            validator,
+            hmac_keystore,
+            presign_secret,
+            access_table,
+            access_table_file,
+            policy,
+            policy_file,
+            callback_workers,
+            callback_queue_limit,
+            callback_timeout,
+            decision_cache,
+            bucket_resolver,
+            bucket_resolver_ttl,
+            bucket_resolver_negative_ttl,
+            bucket_creds_ttl,
+            bucket_creds_negative_ttl,
+            token_cache_file,
+            token_cache_key,
+            redis_url,
         }
     }
+
+    /// Issues a presigned URL on the proxy at `base_url` for `bucket`/`key`, signed with
+    /// `presign_secret`. The proxy honours it without calling the validator.
+    #[pyo3(signature = (base_url, bucket, key, method="GET", expires=3600))]
+    pub fn presign_url(
+        &self,
+        base_url: &str,
+        bucket: &str,
+        key: &str,
+        method: &str,
+        expires: u64,
+    ) -> PyResult<String> {
+        let Some(secret) = &self.presign_secret else {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "presign_secret is not configured",
+            ));
+        };
+        if expires > MAX_PRESIGNED_EXPIRES_SECS {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "expires must not exceed 604800 seconds (7 days)",
+            ));
+        }
+
+        presign_url(
+            base_url,
+            &format!("/{}/{}", bucket, key.trim_start_matches('/')),
+            method,
+            PRESIGN_ACCESS_KEY,
+            secret,
+            "us-east-1",
+            expires,
+            Utc::now(),
+        )
+        .map_err(pyo3::exceptions::PyValueError::new_err)
+    }
+}
+
+/// How the bucket is addressed upstream: in the host name (`bucket.host/key`), as IBM COS and
+/// AWS prefer, or in the path (`host/bucket/key`), as MinIO does by default.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AddressingStyle {
+    #[default]
+    Virtual,
+    Path,
 }
 
-#[derive(FromPyObject, Debug, Clone)]
+impl<'py> FromPyObject<'py> for AddressingStyle {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        match ob.extract::<String>()?.as_str() {
+            "virtual" => Ok(AddressingStyle::Virtual),
+            "path" => Ok(AddressingStyle::Path),
+            other => Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "addressing_style must be 'virtual' or 'path', not '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+#[derive(FromPyObject, Debug, Clone, PartialEq)]
+#[pyo3(from_item_all)]
 pub struct CosMapItem {
     pub host: String,
     pub port: u16,
+    /// Connect to the upstream over TLS; `False` for plain HTTP endpoints such as a local MinIO
+    #[pyo3(default = true)]
+    pub tls: bool,
+    /// Verify the upstream's TLS certificate; `False` for self-signed ones
+    #[pyo3(default = true)]
+    pub verify_cert: bool,
+    #[pyo3(default)]
+    pub addressing_style: AddressingStyle,
+    #[pyo3(default)]
     pub instance: String,
-    pub api_key: Option<String>,
+    /// The secrets may be given as references, see `SecretSource`
+    #[pyo3(default)]
+    pub api_key: Option<SecretSource>,
+    #[pyo3(default)]
+    pub access_key: Option<SecretSource>,
+    #[pyo3(default)]
+    pub secret_key: Option<SecretSource>,
+    #[pyo3(default)]
+    pub region: Option<String>,
+    /// Let requests through when the Python validator fails or times out
+    #[pyo3(default)]
+    pub fail_open: bool,
+    /// IAM token endpoint the `api_key` is exchanged at, instead of the public one
+    #[pyo3(default)]
+    pub iam_url: Option<String>,
+    /// Fixed bearer token
+    #[pyo3(default)]
+    pub token: Option<SecretSource>,
+    /// OAuth2 client credentials to get bearer tokens with
+    #[pyo3(default)]
+    pub oauth2: Option<OAuth2Config>,
+    /// Python callable returning a `(token, expiration)` tuple for the bucket
+    #[pyo3(default)]
+    pub token_provider: Option<TokenCallback>,
+}
+
+impl Default for CosMapItem {
+    fn default() -> Self {
+        CosMapItem {
+            host: String::new(),
+            port: 0,
+            tls: true,
+            verify_cert: true,
+            addressing_style: AddressingStyle::Virtual,
+            instance: String::new(),
+            api_key: None,
+            access_key: None,
+            secret_key: None,
+            region: None,
+            fail_open: false,
+            iam_url: None,
+            token: None,
+            oauth2: None,
+            token_provider: None,
+        }
+    }
+}
+
+impl CosMapItem {
+    /// How many of the ways to get bearer tokens are configured, at most one may be
+    fn token_sources(&self) -> usize {
+        [
+            self.api_key.is_some(),
+            self.token.is_some(),
+            self.oauth2.is_some(),
+            self.token_provider.is_some(),
+        ]
+        .into_iter()
+        .filter(|configured| *configured)
+        .count()
+    }
+
+    /// The secrets of the bucket, references to them included
+    pub fn secrets(&self) -> impl Iterator<Item = &SecretSource> {
+        [
+            &self.api_key,
+            &self.access_key,
+            &self.secret_key,
+            &self.token,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.oauth2.as_ref().map(|oauth2| &oauth2.client_secret))
+    }
+
+    /// The entry with its `dir:` secrets bound to the file of `bucket`
+    pub fn for_bucket(mut self, bucket: &str) -> Self {
+        for secret in [
+            &mut self.api_key,
+            &mut self.access_key,
+            &mut self.secret_key,
+            &mut self.token,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.oauth2.as_mut().map(|oauth2| &mut oauth2.client_secret))
+        {
+            *secret = secret.for_bucket(bucket);
+        }
+        self
+    }
+
+    /// HMAC access/secret key pair, if the bucket is to be accessed with SigV4 instead of a bearer token
+    pub fn hmac_credentials(&self) -> Option<(&SecretSource, &SecretSource)> {
+        match (&self.access_key, &self.secret_key) {
+            (Some(access_key), Some(secret_key)) => Some((access_key, secret_key)),
+            _ => None,
+        }
+    }
+
+    /// Region used in the SigV4 credential scope. Falls back to the region in
+    /// `s3.<region>.<domain>` style hosts (optionally `s3.direct.` / `s3.private.`),
+    /// and to `us-east-1`, which is what MinIO expects by default.
+    pub fn signing_region(&self) -> String {
+        if let Some(region) = &self.region {
+            return region.clone();
+        }
+        let mut labels = self.host.split('.');
+        if labels.next() == Some("s3") {
+            let region = match labels.next() {
+                Some("direct") | Some("private") => labels.next(),
+                label => label,
+            };
+            if let Some(region) = region
+                && region.contains('-')
+            {
+                return region.to_string();
+            }
+        }
+        "us-east-1".to_string()
+    }
 }
 
+type CosMapTuple = (String, String, u16, String, Option<String>);
+
+/// Accepts either a list of `(bucket, host, port, instance, api_key)` tuples or a dict of
+/// bucket name to a dict with the `CosMapItem` fields.
 fn parse_cos_map(py: Python, cos_dict: &PyObject) -> PyResult<HashMap<String, CosMapItem>> {
+    if cos_dict.bind(py).is_instance_of::<PyDict>() {
+        let cos_map: HashMap<String, CosMapItem> = cos_dict.extract(py).inspect_err(|e| {
+            error!("Error extracting cos_map: {:?}", e);
+        })?;
+        let cos_map: HashMap<String, CosMapItem> = cos_map
+            .into_iter()
+            .map(|(bucket, item)| {
+                let item = item.for_bucket(&bucket);
+                (bucket, item)
+            })
+            .collect();
+        if let Some((bucket, _)) = cos_map.iter().find(|(_, item)| item.token_sources() > 1) {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "bucket {} may configure only one of api_key, token, oauth2 and token_provider",
+                bucket
+            )));
+        }
+        return Ok(cos_map);
+    }
+
     let mut cos_map: HashMap<String, CosMapItem> = HashMap::new();
-    let cos_tuples: Result<Vec<(String, String, u16, String, Option<String>)>, PyErr> =
-        cos_dict.extract(py);
+    let cos_tuples: Result<Vec<CosMapTuple>, PyErr> = cos_dict.extract(py);
 
     match cos_tuples {
         Ok(cos_tuples) => {
             for (bucket, host, port, instance, api_key) in cos_tuples {
                 let host = host.to_string();
                 let instance = instance.to_string();
-                let port = port;
                 let bucket = bucket.to_string();
-                let api_key = api_key.map(|s| s.to_string());
+                let api_key = api_key.as_deref().map(SecretSource::parse);
 
                 cos_map.insert(
                     bucket.clone(),
@@ -110,7 +485,9 @@ fn parse_cos_map(py: Python, cos_dict: &PyObject) -> PyResult<HashMap<String, Co
                         port,
                         instance: instance.clone(),
                         api_key: api_key.clone(),
-                    },
+                        ..Default::default()
+                    }
+                    .for_bucket(&bucket),
                 );
             }
 
@@ -123,17 +500,334 @@ fn parse_cos_map(py: Python, cos_dict: &PyObject) -> PyResult<HashMap<String, Co
     }
 }
 
+/// Reads the access key -> secret key mapping used to verify inbound SigV4 signatures.
+fn parse_hmac_keystore(py: Python, keystore: &PyObject) -> PyResult<HashMap<String, String>> {
+    keystore.extract(py).inspect_err(|e| {
+        error!("Error extracting hmac_keystore: {:?}", e);
+    })
+}
+
+/// Picks the validator from the config: the Python `validator` callback, a static
+/// `access_table` (access key -> buckets), an `access_table_file`, or allow-all.
+fn build_validator(
+    py: Python,
+    run_args: &ProxyServerConfig,
+    pool: &Arc<CallbackPool>,
+) -> PyResult<Arc<dyn Validator>> {
+    let configured = [
+        run_args.validator.is_some(),
+        run_args.access_table.is_some(),
+        run_args.access_table_file.is_some(),
+    ];
+    if configured.iter().filter(|c| **c).count() > 1 {
+        return Err(pyo3::exceptions::PyValueError::new_err(
+            "validator, access_table and access_table_file are mutually exclusive",
+        ));
+    }
+
+    if let Some(callback) = &run_args.validator {
+        info!("Using Python validator callback");
+        return Ok(Arc::new(PythonValidator::new(
+            py,
+            callback.clone_ref(py),
+            pool.clone(),
+        )));
+    }
+    if let Some(table) = &run_args.access_table {
+        let table: AccessTable = table.extract(py).inspect_err(|e| {
+            error!("Error extracting access_table: {:?}", e);
+        })?;
+        info!("Using static access table with {} access keys", table.len());
+        return Ok(Arc::new(StaticValidator::new(table)));
+    }
+    if let Some(path) = &run_args.access_table_file {
+        let validator =
+            FileValidator::new(path).map_err(pyo3::exceptions::PyValueError::new_err)?;
+        return Ok(Arc::new(validator));
+    }
+
+    info!("No validator provided; allowing all requests");
+    Ok(Arc::new(AllowAllValidator))
+}
+
+/// Bounds how many Python callbacks run at once, how many may queue and how long they take.
+fn build_callback_pool(run_args: &ProxyServerConfig) -> PyResult<CallbackPool> {
+    if run_args.callback_workers == 0 {
+        return Err(pyo3::exceptions::PyValueError::new_err(
+            "callback_workers must be at least 1",
+        ));
+    }
+    let timeout = Duration::try_from_secs_f64(run_args.callback_timeout)
+        .ok()
+        .filter(|timeout| !timeout.is_zero())
+        .ok_or_else(|| {
+            pyo3::exceptions::PyValueError::new_err("callback_timeout must be a positive number")
+        })?;
+    Ok(CallbackPool::new(
+        run_args.callback_workers,
+        run_args.callback_queue_limit,
+        timeout,
+    ))
+}
+
+/// Wraps the Python `bucket_resolver` in a cache that keeps known buckets for
+/// `bucket_resolver_ttl` seconds and unknown ones for `bucket_resolver_negative_ttl`.
+fn build_bucket_resolver(
+    py: Python,
+    run_args: &ProxyServerConfig,
+    pool: &Arc<CallbackPool>,
+) -> PyResult<Option<Arc<dyn BucketResolver>>> {
+    let Some(callback) = &run_args.bucket_resolver else {
+        return Ok(None);
+    };
+    let resolver = PythonBucketResolver::new(callback.clone_ref(py), pool.clone());
+    Ok(Some(Arc::new(CachedResolver::new(
+        Arc::new(resolver),
+        ttl(run_args.bucket_resolver_ttl, "bucket_resolver_ttl")?,
+        ttl(
+            run_args.bucket_resolver_negative_ttl,
+            "bucket_resolver_negative_ttl",
+        )?,
+    ))))
+}
+
+fn ttl(seconds: f64, name: &str) -> PyResult<Duration> {
+    Duration::try_from_secs_f64(seconds).map_err(|_| {
+        pyo3::exceptions::PyValueError::new_err(format!(
+            "{} must be a non-negative number of seconds",
+            name
+        ))
+    })
+}
+
+/// Wraps the Python `bucket_creds_fetcher`, if any, remembering API keys for
+/// `bucket_creds_ttl` seconds and buckets without credentials for `bucket_creds_negative_ttl`.
+fn build_creds_fetcher(
+    py: Python,
+    run_args: &ProxyServerConfig,
+    pool: &Arc<CallbackPool>,
+) -> PyResult<Option<BucketCredsFetcher>> {
+    let Some(callback) = &run_args.bucket_creds_fetcher else {
+        return Ok(None);
+    };
+    Ok(Some(BucketCredsFetcher::new(
+        callback.clone_ref(py),
+        pool.clone(),
+        ttl(run_args.bucket_creds_ttl, "bucket_creds_ttl")?,
+        ttl(
+            run_args.bucket_creds_negative_ttl,
+            "bucket_creds_negative_ttl",
+        )?,
+    )))
+}
+
+/// A token cache shared with other proxies through the Redis at `redis_url`, or kept in
+/// `token_cache_file`, in either case encrypted with `token_cache_key`, when configured; an
+/// in-memory one otherwise.
+fn build_secrets_cache(run_args: &ProxyServerConfig) -> PyResult<SecretsCache> {
+    let key = run_args
+        .token_cache_key
+        .as_deref()
+        .map(token_file::parse_key)
+        .transpose()
+        .map_err(pyo3::exceptions::PyValueError::new_err)?;
+    match (&run_args.redis_url, &run_args.token_cache_file, key) {
+        (Some(_), Some(_), _) => Err(pyo3::exceptions::PyValueError::new_err(
+            "redis_url and token_cache_file are mutually exclusive",
+        )),
+        (Some(url), None, Some(key)) => {
+            let store =
+                RedisTokenStore::new(url, key).map_err(pyo3::exceptions::PyValueError::new_err)?;
+            info!("Sharing bearer tokens through Redis");
+            Ok(SecretsCache::with_store(Arc::new(store)))
+        }
+        (Some(_), None, None) => Err(pyo3::exceptions::PyValueError::new_err(
+            "redis_url needs a token_cache_key to encrypt the tokens with",
+        )),
+        (None, Some(path), Some(key)) => {
+            info!("Keeping bearer tokens in {}", path);
+            let store = MemoryTokenStore::with_file(EncryptedTokenFile::new(path, key));
+            Ok(SecretsCache::with_store(Arc::new(store)))
+        }
+        (None, None, None) => Ok(SecretsCache::new()),
+        _ => Err(pyo3::exceptions::PyValueError::new_err(
+            "token_cache_file and token_cache_key must be given together",
+        )),
+    }
+}
+
+/// Loads the policy document from the config, given either as a dict (or JSON string)
+/// in `policy` or as a JSON file in `policy_file`.
+fn build_policy(py: Python, run_args: &ProxyServerConfig) -> PyResult<Option<Policy>> {
+    let policy = match (&run_args.policy, &run_args.policy_file) {
+        (Some(_), Some(_)) => {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "policy and policy_file are mutually exclusive",
+            ));
+        }
+        (Some(document), None) => {
+            let json = match document.extract::<String>(py) {
+                Ok(json) => json,
+                Err(_) => py
+                    .import("json")?
+                    .call_method1("dumps", (document,))?
+                    .extract()?,
+            };
+            Policy::from_json(&json)
+        }
+        (None, Some(path)) => Policy::from_file(path),
+        (None, None) => return Ok(None),
+    }
+    .map_err(pyo3::exceptions::PyValueError::new_err)?;
+
+    // without verifying signatures, any client could claim to be any principal
+    if run_args.hmac_keystore.is_none() {
+        return Err(pyo3::exceptions::PyValueError::new_err(
+            "policy and policy_file require an hmac_keystore to verify the principal",
+        ));
+    }
+    info!("Using policy with {} statements", policy.len());
+    Ok(Some(policy))
+}
+
+/// Whether a request may be sent upstream twice; these methods carry no body with S3,
+/// so there is nothing that would have to be buffered for the retry.
+fn is_idempotent(method: &http::Method) -> bool {
+    matches!(
+        *method,
+        http::Method::GET | http::Method::HEAD | http::Method::OPTIONS | http::Method::DELETE
+    )
+}
+
+/// The decision of a validator, or the S3 error to answer with when it failed: `SlowDown`
+/// when the call was shed, otherwise `ServiceUnavailable`, unless the bucket is configured
+/// to `fail_open` and let the request through.
+fn validation_outcome(
+    result: std::result::Result<AuthDecision, CallbackError>,
+    fail_open: bool,
+    bucket: &str,
+) -> std::result::Result<AuthDecision, S3Error> {
+    match result {
+        Ok(decision) => Ok(decision),
+        Err(CallbackError::Overloaded) => Err(S3Error::slow_down()),
+        Err(e) if fail_open => {
+            warn!(
+                "Validator failed for bucket {}, failing open: {}",
+                bucket, e
+            );
+            Ok(AuthDecision::from(true))
+        }
+        Err(e) => {
+            error!("Validator failed for bucket {}: {}", bucket, e);
+            Err(S3Error::service_unavailable())
+        }
+    }
+}
+
+/// Most of an S3 error body kept to find its error code in
+const MAX_ERROR_BODY: usize = 8 * 1024;
+
+/// Drops the credentials the upstream rejected, once per request: the bearer token and,
+/// when it was fetched with an API key from the `bucket_creds_fetcher`, that key too, as it
+/// may have been rotated or revoked. When another request dropped them a moment ago the
+/// current ones are kept. Returns whether the credentials were rejected, i.e. whether the
+/// request may be retried with the new or current token.
+async fn drop_rejected_credentials(
+    status: u16,
+    code: Option<&str>,
+    creds_fetcher: Option<&BucketCredsFetcher>,
+    ctx: &mut MyCtx,
+) -> bool {
+    if !rejects_credentials(status, code) || ctx.auth_retried {
+        return false;
+    }
+    // only bearer tokens are renewed; HMAC keys are what they are
+    let Some((token_key, token)) = ctx.bearer_token.take() else {
+        return false;
+    };
+    ctx.auth_retried = true;
+    warn!(
+        "Upstream rejected the token for {} with {} {}",
+        token_key,
+        status,
+        code.unwrap_or_default()
+    );
+    if !ctx
+        .secrets_cache
+        .invalidate_rejected(&token_key, &token)
+        .await
+    {
+        info!("Token for {} was renewed already", token_key);
+        return true;
+    }
+    if let (Some(creds_fetcher), Some(bucket)) = (creds_fetcher, ctx.fetched_creds_bucket.take()) {
+        info!("Dropping the fetched credentials of bucket {}", bucket);
+        creds_fetcher.invalidate(&bucket);
+    }
+    true
+}
+
+/// Stores the S3 error `fail_to_proxy` answers the client with once the filter bails out.
+fn fail_with(
+    slot: &mut Option<S3Error>,
+    error: S3Error,
+    reason: &'static str,
+) -> Box<pingora::Error> {
+    *slot = Some(error);
+    pingora::Error::new_str(reason)
+}
+
 pub struct MyProxy {
     cos_endpoint: String,
     cos_mapping: HashMap<String, CosMapItem>,
     secrets_cache: SecretsCache,
-    validator: Option<PyObject>,
+    validator: Arc<dyn Validator>,
+    hmac_keystore: Option<HashMap<String, String>>,
+    presign_keystore: Option<HashMap<String, String>>,
+    policy: Option<Policy>,
+    creds_fetcher: Option<BucketCredsFetcher>,
+    bucket_resolver: Option<Arc<dyn BucketResolver>>,
+    providers: ProviderCache,
+}
+
+impl MyProxy {
+    /// The configuration of `bucket`: from the static cos map, else from the bucket
+    /// resolver. `None` when neither knows the bucket.
+    async fn bucket_config(
+        &self,
+        bucket: &str,
+    ) -> std::result::Result<Option<CosMapItem>, CallbackError> {
+        if let Some(config) = self.cos_mapping.get(bucket) {
+            return Ok(Some(config.clone()));
+        }
+        match &self.bucket_resolver {
+            Some(resolver) => resolver.resolve(bucket).await,
+            None => Ok(None),
+        }
+    }
 }
 
 pub struct MyCtx {
-    cos_mapping: HashMap<String, CosMapItem>,
+    /// Configuration of the bucket the request goes to, resolved in `upstream_peer`
+    bucket_config: Option<CosMapItem>,
     secrets_cache: SecretsCache,
-    validator: Option<PyObject>,
+    aws_chunked: Option<AwsChunkedDecoder>,
+    upstream_headers: HashMap<String, String>,
+    bucket_override: Option<String>,
+    request_id: String,
+    s3_error: Option<S3Error>,
+    /// Cache key and value of the bearer token the upstream request was sent with
+    bearer_token: Option<(String, String)>,
+    /// Whether the request has been retried already after the upstream rejected its token
+    auth_retried: bool,
+    /// The bucket whose API key, from the `bucket_creds_fetcher`, the bearer token was
+    /// fetched with
+    fetched_creds_bucket: Option<String>,
+    /// Checks the body against its `x-amz-content-sha256` header
+    payload_verifier: Option<PayloadVerifier>,
+    /// Status and body so far of an upstream error that may turn out to have rejected the
+    /// bearer token, once its error code is read
+    error_response: Option<(u16, Vec<u8>)>,
 }
 
 #[async_trait]
@@ -141,101 +835,312 @@ impl ProxyHttp for MyProxy {
     type CTX = MyCtx;
     fn new_ctx(&self) -> Self::CTX {
         MyCtx {
-            cos_mapping: self.cos_mapping.clone(),
+            bucket_config: None,
             secrets_cache: self.secrets_cache.clone(),
-            validator: self
-                .validator
-                .as_ref()
-                .map(|v| Python::with_gil(|py| v.clone_ref(py))),
+            aws_chunked: None,
+            upstream_headers: HashMap::new(),
+            bucket_override: None,
+            request_id: new_request_id(),
+            s3_error: None,
+            bearer_token: None,
+            auth_retried: false,
+            fetched_creds_bucket: None,
+            payload_verifier: None,
+            error_response: None,
         }
     }
 
-
-    async fn request_filter(
-        &self,
-        session: &mut Session,
-        ctx: &mut Self::CTX,
-    ) -> Result<bool> {
-
+    async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
         let path = session.req_header().uri.path();
 
-        let parse_path_result = parse_path(path);
-        if parse_path_result.is_err() {
-            error!("Failed to parse path: {:?}", parse_path_result);
-            return Err(pingora::Error::new_str("Failed to parse path"));
-        }
-
-        let (_, (bucket, _)) = parse_path(path).unwrap();
+        let Ok((_, (bucket, object_path))) = parse_path(path) else {
+            error!("Failed to parse path: {}", path);
+            S3Error::no_such_bucket()
+                .respond(session, &ctx.request_id)
+                .await?;
+            return Ok(true);
+        };
 
         let auth_header = session
             .req_header()
             .headers
             .get("authorization")
-            .map(|h| h.to_str().unwrap())
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("");
+
+        let query = session.req_header().uri.query().unwrap_or("");
+
+        let proxy_presigned = is_proxy_presigned(query);
+
+        let signature_check = match (&self.hmac_keystore, &self.presign_keystore) {
+            (_, Some(presign_keystore)) if proxy_presigned => {
+                verify_signature(session.req_header(), presign_keystore).map(|_| ())
+            }
+            (Some(keystore), _) => verify_signature(session.req_header(), keystore).map(|_| ()),
+            (None, _) if is_presigned(query) => parse_presigned_params(query)
+                .map_err(SignatureError::Malformed)
+                .and_then(|params| check_presigned_expiry(&params)),
+            (None, _) => check_authorization_header(auth_header),
+        };
+
+        if let Err(e) = signature_check {
+            error!("Signature verification failed for bucket {}: {}", bucket, e);
+            e.s3_error().respond(session, &ctx.request_id).await?;
+            return Ok(true);
+        }
+
+        let content_sha256 = session
+            .req_header()
+            .headers
+            .get("x-amz-content-sha256")
+            .and_then(|h| h.to_str().ok())
             .unwrap_or("");
+        ctx.payload_verifier = PayloadVerifier::new(content_sha256);
+        if content_sha256.starts_with("STREAMING-") {
+            if !session
+                .req_header()
+                .headers
+                .contains_key("x-amz-decoded-content-length")
+            {
+                S3Error::new(
+                    411,
+                    "MissingContentLength",
+                    "You must provide the x-amz-decoded-content-length HTTP header.",
+                )
+                .respond(session, &ctx.request_id)
+                .await?;
+                return Ok(true);
+            }
 
-        let is_authorized = if let Some(py_cb) = &ctx.validator {
-            Python::with_gil(|py| {
-                crate::utils::validator::validate_request(
-                    auth_header,
-                    &bucket,
-                    py,
-                    py_cb,
+            let chunk_signer = match &self.hmac_keystore {
+                Some(keystore) if content_sha256.starts_with(STREAMING_PAYLOAD) => {
+                    match streaming_chunk_signer(session.req_header(), keystore) {
+                        Ok(chunk_signer) => Some(chunk_signer),
+                        Err(e) => {
+                            e.s3_error().respond(session, &ctx.request_id).await?;
+                            return Ok(true);
+                        }
+                    }
+                }
+                _ => None,
+            };
+            ctx.aws_chunked = Some(match chunk_signer {
+                Some(chunk_signer) if content_sha256 == STREAMING_PAYLOAD_TRAILER => {
+                    AwsChunkedDecoder::with_signed_trailer(chunk_signer)
+                }
+                chunk_signer => AwsChunkedDecoder::new(chunk_signer),
+            });
+        }
+
+        if proxy_presigned && self.presign_keystore.is_some() {
+            info!("Honouring proxy-issued presigned url for bucket {}", bucket);
+            return Ok(false);
+        }
+
+        let access_key = match extract_access_key(auth_header, query) {
+            Ok(access_key) => access_key,
+            Err(e) => {
+                error!("Failed to extract access key: {}", e);
+                S3Error::new(
+                    400,
+                    "AuthorizationHeaderMalformed",
+                    "The authorization header is malformed.",
                 )
-                .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e))
-                .and_then(|ok| Ok(ok))
-            })
-            .map_err(|_| pingora::Error::new_str("Python validator panicked"))?
-        } else {
-            true
+                .respond(session, &ctx.request_id)
+                .await?;
+                return Ok(true);
+            }
+        };
+
+        let req = session.req_header();
+        let key = percent_decode_str(object_path.trim_start_matches('/')).decode_utf8_lossy();
+        let operation = S3Operation::classify(
+            req.method.as_str(),
+            &key,
+            query,
+            req.headers.contains_key("x-amz-copy-source"),
+        );
+        let access_request = AccessRequest {
+            access_key: access_key.as_deref(),
+            bucket,
+            operation,
+            key: Some(key.as_ref()).filter(|k| !k.is_empty()),
+            client_addr: session
+                .client_addr()
+                .and_then(|addr| addr.as_inet())
+                .map(|addr| addr.ip()),
+        };
+
+        if let Some(policy) = &self.policy {
+            let evaluation = policy.evaluate(&access_request, Utc::now());
+            if evaluation != PolicyEvaluation::Allow {
+                info!(
+                    "Policy denies {:?} {} on bucket {}: {:?}",
+                    access_key, operation, bucket, evaluation
+                );
+                S3Error::access_denied()
+                    .respond(session, &ctx.request_id)
+                    .await?;
+                return Ok(true);
+            }
+        }
+
+        let result = self.validator.validate(&access_request).await;
+        let fail_open = match &result {
+            Err(e) if *e != CallbackError::Overloaded => {
+                matches!(self.bucket_config(bucket).await, Ok(Some(config)) if config.fail_open)
+            }
+            _ => false,
+        };
+        let decision = match validation_outcome(result, fail_open, bucket) {
+            Ok(decision) => decision,
+            Err(s3_error) => {
+                s3_error.respond(session, &ctx.request_id).await?;
+                return Ok(true);
+            }
         };
 
-        if !is_authorized {
-            session.respond_error(401).await?;
+        if !decision.allow {
+            info!(
+                "Access denied for {:?} on {} in bucket {}: {}",
+                access_key,
+                operation,
+                bucket,
+                decision.reason.as_deref().unwrap_or("no reason given")
+            );
+            decision.denial().respond(session, &ctx.request_id).await?;
             return Ok(true);
         }
 
+        if let Some(bucket_override) = &decision.bucket {
+            info!(
+                "Validator redirects bucket {} to {}",
+                bucket, bucket_override
+            );
+        }
+        ctx.upstream_headers = decision.headers;
+        ctx.bucket_override = decision.bucket;
+
         Ok(false)
     }
 
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let Some(verifier) = ctx.payload_verifier.as_mut() {
+            if let Some(data) = body.as_ref() {
+                verifier.update(data);
+            }
+            // fails the request before its end reaches the upstream, which then drops it
+            if end_of_stream
+                && !ctx
+                    .payload_verifier
+                    .take()
+                    .is_some_and(PayloadVerifier::verify)
+            {
+                error!("Request body does not match its x-amz-content-sha256 header");
+                return Err(fail_with(
+                    &mut ctx.s3_error,
+                    S3Error::content_sha256_mismatch(),
+                    "Payload hash mismatch",
+                ));
+            }
+        }
+
+        let Some(decoder) = ctx.aws_chunked.as_mut() else {
+            return Ok(());
+        };
+
+        if let Some(data) = body.take() {
+            match decoder.decode(&data) {
+                Ok(decoded) => *body = Some(decoded),
+                Err(e) => {
+                    error!("Failed to decode aws-chunked body: {}", e);
+                    return Err(fail_with(
+                        &mut ctx.s3_error,
+                        S3Error::invalid_request("The aws-chunked request body is invalid."),
+                        "Invalid aws-chunked body",
+                    ));
+                }
+            }
+        }
+
+        if end_of_stream && let Err(e) = decoder.finish() {
+            error!("Failed to decode aws-chunked body: {}", e);
+            return Err(fail_with(
+                &mut ctx.s3_error,
+                S3Error::invalid_request("The aws-chunked request body is invalid."),
+                "Invalid aws-chunked body",
+            ));
+        }
+
+        Ok(())
+    }
 
     async fn upstream_peer(
         &self,
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
-        let mut req_counter = REQ_COUNTER.lock().unwrap();
-        *req_counter += 1;
-
+        *REQ_COUNTER.lock().unwrap() += 1;
 
         let path = session.req_header().uri.path();
 
-        let parse_path_result = parse_path(path);
-        if parse_path_result.is_err() {
-            error!("Failed to parse path: {:?}", parse_path_result);
-            return Err(pingora::Error::new_str("Failed to parse path"));
-        }
-
-        let (_, (bucket, _)) = parse_path(path).unwrap();
+        let Ok((_, (bucket, _))) = parse_path(path) else {
+            error!("Failed to parse path: {}", path);
+            return Err(fail_with(
+                &mut ctx.s3_error,
+                S3Error::no_such_bucket(),
+                "Failed to parse path",
+            ));
+        };
+        let bucket = ctx.bucket_override.as_deref().unwrap_or(bucket);
 
         let hdr_bucket = bucket.to_owned();
 
-
-        let bucket_config = ctx.cos_mapping.get(&hdr_bucket);
-        let endpoint = match bucket_config {
-            Some(config) => {
-                format!("{}", config.host)
+        ctx.bucket_config = match self.bucket_config(&hdr_bucket).await {
+            Ok(Some(config)) => Some(config),
+            Ok(None) if self.bucket_resolver.is_some() => {
+                error!("Bucket resolver does not know bucket: {}", hdr_bucket);
+                return Err(fail_with(
+                    &mut ctx.s3_error,
+                    S3Error::no_such_bucket(),
+                    "Unknown bucket",
+                ));
             }
-            None => {
-                format!("{}.{}", bucket, self.cos_endpoint)
+            Ok(None) => None,
+            Err(e) => {
+                error!("Failed to resolve bucket {}: {}", hdr_bucket, e);
+                let s3_error = match e {
+                    CallbackError::Overloaded => S3Error::slow_down(),
+                    _ => S3Error::service_unavailable(),
+                };
+                return Err(fail_with(
+                    &mut ctx.s3_error,
+                    s3_error,
+                    "Failed to resolve bucket",
+                ));
             }
         };
+        let (endpoint, port, tls, verify_cert) = match &ctx.bucket_config {
+            Some(config) => (
+                config.host.to_string(),
+                config.port,
+                config.tls,
+                config.verify_cert,
+            ),
+            None => (format!("{}.{}", bucket, self.cos_endpoint), 443, true, true),
+        };
         dbg!(&endpoint);
 
-        let addr = (endpoint.clone(), 443);
+        let addr = (endpoint.clone(), port);
 
-        let mut peer = Box::new(HttpPeer::new(addr, true, endpoint.clone()));
-        peer.options.verify_cert = false;
+        let mut peer = Box::new(HttpPeer::new(addr, tls, endpoint.clone()));
+        peer.options.verify_cert = verify_cert;
         Ok(peer)
     }
 
@@ -245,45 +1150,128 @@ impl ProxyHttp for MyProxy {
         upstream_request: &mut pingora::http::RequestHeader,
         ctx: &mut Self::CTX,
     ) -> Result<()> {
-
         dbg!(&session.req_header());
 
-        let (_, (bucket, my_updated_url)) = parse_path(upstream_request.uri.path()).unwrap();
+        let Ok((_, (bucket, my_updated_url))) = parse_path(upstream_request.uri.path()) else {
+            error!("Failed to parse path: {}", upstream_request.uri.path());
+            return Err(fail_with(
+                &mut ctx.s3_error,
+                S3Error::no_such_bucket(),
+                "Failed to parse path",
+            ));
+        };
+        let bucket = ctx.bucket_override.as_deref().unwrap_or(bucket);
 
         let hdr_bucket = bucket.to_string();
 
-        let my_query = match upstream_request.uri.query() {
+        let my_query = match upstream_request.uri.query().map(strip_presigned_params) {
             Some(q) if !q.is_empty() => format!("?{}", q),
             _ => String::new(),
         };
 
-        let bucket_config = ctx.cos_mapping.get(&hdr_bucket);
+        let bucket_config = ctx.bucket_config.as_ref();
 
-        let endpoint = match bucket_config {
-            Some(config) => {
-                format!("{}.{}", bucket, config.host)
-            }
-            None => {
-                format!("{}.{}", bucket, self.cos_endpoint)
+        let (endpoint, path) = match bucket_config {
+            Some(config) if config.addressing_style == AddressingStyle::Path => {
+                let path = match my_updated_url {
+                    "/" => format!("/{}", bucket),
+                    key => format!("/{}{}", bucket, key),
+                };
+                (config.host.clone(), path)
             }
+            Some(config) => (
+                format!("{}.{}", bucket, config.host),
+                my_updated_url.to_string(),
+            ),
+            None => (
+                format!("{}.{}", bucket, self.cos_endpoint),
+                my_updated_url.to_string(),
+            ),
         };
-        let api_key = match bucket_config {
-            Some(config) => config.api_key.clone(),
-            None => None,
-        };
+        let hmac_credentials = bucket_config.and_then(|config| config.hmac_credentials());
 
-        let Some(api_key) = api_key else {
-            error!("No API key configured for bucket: {}", hdr_bucket);
-            return Err(pingora::Error::new_str("No API key configured for bucket"));
+        // also reads the secret files of new buckets, the HMAC ones included
+        let configured = match bucket_config {
+            Some(config) => self.providers.get(&hdr_bucket, config).await,
+            None => None,
         };
 
-        let bearer_fetcher = {
-            let api_key = api_key.clone();
-            move || get_bearer(api_key.clone())
+        let bearer_token = if hmac_credentials.is_some() {
+            None
+        } else {
+            let provider = match (configured, &self.creds_fetcher) {
+                (Some(provider), _) => provider,
+                (None, Some(creds_fetcher)) => match creds_fetcher.get(&hdr_bucket).await {
+                    Ok(Some(api_key)) => {
+                        ctx.fetched_creds_bucket = Some(hdr_bucket.clone());
+                        let iam_url = bucket_config.and_then(|config| config.iam_url.as_deref());
+                        Arc::new(IbmIamProvider::new(iam_url, SecretSource::Inline(api_key)))
+                            as Arc<dyn TokenProvider>
+                    }
+                    Ok(None) => {
+                        error!("No credentials for bucket: {}", hdr_bucket);
+                        return Err(fail_with(
+                            &mut ctx.s3_error,
+                            S3Error::access_denied(),
+                            "No credentials for bucket",
+                        ));
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to fetch credentials for bucket {}: {}",
+                            hdr_bucket, e
+                        );
+                        let s3_error = match e {
+                            CallbackError::Overloaded => S3Error::slow_down(),
+                            _ => S3Error::service_unavailable(),
+                        };
+                        return Err(fail_with(
+                            &mut ctx.s3_error,
+                            s3_error,
+                            "Failed to fetch bucket credentials",
+                        ));
+                    }
+                },
+                (None, None) if bucket_config.is_none() => {
+                    error!("No configuration for bucket: {}", hdr_bucket);
+                    return Err(fail_with(
+                        &mut ctx.s3_error,
+                        S3Error::no_such_bucket(),
+                        "Unknown bucket",
+                    ));
+                }
+                (None, None) => {
+                    error!("No credentials configured for bucket: {}", hdr_bucket);
+                    return Err(fail_with(
+                        &mut ctx.s3_error,
+                        S3Error::access_denied(),
+                        "No credentials configured for bucket",
+                    ));
+                }
+            };
+
+            let token_key = provider.cache_key();
+            let bearer_fetcher = move || {
+                let provider = provider.clone();
+                async move { provider.fetch_token().await }
+            };
+
+            match ctx.secrets_cache.get(&token_key, bearer_fetcher).await {
+                Some(token) => {
+                    ctx.bearer_token = Some((token_key, token.clone()));
+                    Some(token)
+                }
+                None => {
+                    error!("No bearer token available for bucket: {}", hdr_bucket);
+                    return Err(fail_with(
+                        &mut ctx.s3_error,
+                        S3Error::service_unavailable(),
+                        "Failed to get bearer token",
+                    ));
+                }
+            }
         };
 
-        let bearer_token = ctx.secrets_cache.get(&hdr_bucket, bearer_fetcher).await;
-
         // Box:leak the temporary string to get a static reference which will outlive the function
         let authority = Authority::from_static(Box::leak(endpoint.clone().into_boxed_str()));
 
@@ -297,17 +1285,171 @@ impl ProxyHttp for MyProxy {
                         .clone(),
                 )
                 .scheme(upstream_request.uri.scheme_str().unwrap_or("https"))
-                .path_and_query(my_updated_url.to_owned() + (&my_query))
+                .path_and_query(path + (&my_query))
                 .build()
                 .unwrap(),
         );
 
         upstream_request.insert_header("host", endpoint.to_owned())?;
 
-        upstream_request
-            .insert_header("Authorization", format!("Bearer {}", bearer_token.unwrap()))?;
+        if ctx.aws_chunked.is_some() {
+            // the body is forwarded decoded, so describe it as a plain payload
+            let decoded_length = upstream_request
+                .headers
+                .get("x-amz-decoded-content-length")
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("0")
+                .to_string();
+            let content_encoding = upstream_request
+                .headers
+                .get("content-encoding")
+                .and_then(|h| h.to_str().ok())
+                .map(|encodings| {
+                    encodings
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|e| !e.eq_ignore_ascii_case("aws-chunked") && !e.is_empty())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                })
+                .unwrap_or_default();
+
+            if content_encoding.is_empty() {
+                upstream_request.remove_header("content-encoding");
+            } else {
+                upstream_request.insert_header("content-encoding", content_encoding)?;
+            }
+            upstream_request.remove_header("x-amz-decoded-content-length");
+            // the trailer is dropped with its checksum, which arrives after the headers went
+            // out, so the upstream is not to expect one
+            upstream_request.remove_header("x-amz-trailer");
+            upstream_request.remove_header("x-amz-sdk-checksum-algorithm");
+            upstream_request.remove_header("transfer-encoding");
+            upstream_request.insert_header("content-length", decoded_length)?;
+            upstream_request.insert_header("x-amz-content-sha256", UNSIGNED_PAYLOAD)?;
+        }
+
+        for (name, value) in &ctx.upstream_headers {
+            upstream_request.insert_header(name.to_owned(), value)?;
+        }
+
+        match (hmac_credentials, bucket_config, bearer_token) {
+            (Some((access_key, secret_key)), Some(config), _) => {
+                let (access_key, secret_key) = match (access_key.resolve(), secret_key.resolve()) {
+                    (Ok(access_key), Ok(secret_key)) => (access_key, secret_key),
+                    (Err(e), _) | (_, Err(e)) => {
+                        error!("No HMAC credentials for bucket {}: {}", hdr_bucket, e);
+                        return Err(fail_with(
+                            &mut ctx.s3_error,
+                            S3Error::service_unavailable(),
+                            "Failed to read bucket credentials",
+                        ));
+                    }
+                };
+                sign_request(
+                    upstream_request,
+                    &access_key,
+                    &secret_key,
+                    &config.signing_region(),
+                    Utc::now(),
+                )?;
+            }
+            (_, _, Some(bearer_token)) => {
+                upstream_request
+                    .insert_header("Authorization", format!("Bearer {}", bearer_token))?;
+            }
+            _ => {
+                error!("No upstream credentials for bucket: {}", hdr_bucket);
+                return Err(fail_with(
+                    &mut ctx.s3_error,
+                    S3Error::service_unavailable(),
+                    "No upstream credentials",
+                ));
+            }
+        }
         Ok(())
     }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let status = upstream_response.status.as_u16();
+        let code = header_error_code(upstream_response);
+        if !drop_rejected_credentials(status, code, self.creds_fetcher.as_ref(), ctx).await {
+            // the error code of a 400 or 403 is usually in the body, which arrives once the
+            // response is on its way to the client: too late for a retry, but the next
+            // request can still get new credentials
+            if matches!(status, 400 | 403) && ctx.bearer_token.is_some() && !ctx.auth_retried {
+                ctx.error_response = Some((status, Vec::new()));
+            }
+            return Ok(());
+        }
+        let method = &session.req_header().method;
+        if !is_idempotent(method) {
+            info!("Not retrying {} request {}", method, ctx.request_id);
+            return Ok(());
+        }
+        // nothing has been sent to the client yet, so the request can be proxied again
+        let mut e = pingora::Error::explain(
+            pingora::ErrorType::HTTPStatus(status),
+            "Upstream rejected the bearer token",
+        );
+        e.set_retry(true);
+        Err(e)
+    }
+
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>> {
+        if let (Some((_, error_body)), Some(data)) = (ctx.error_response.as_mut(), body.as_ref()) {
+            let room = MAX_ERROR_BODY.saturating_sub(error_body.len());
+            error_body.extend_from_slice(&data[..data.len().min(room)]);
+        }
+        Ok(None)
+    }
+
+    async fn logging(
+        &self,
+        _session: &mut Session,
+        _e: Option<&pingora::Error>,
+        ctx: &mut Self::CTX,
+    ) {
+        if let Some((status, body)) = ctx.error_response.take() {
+            drop_rejected_credentials(status, error_code(&body), self.creds_fetcher.as_ref(), ctx)
+                .await;
+        }
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &pingora::Error,
+        ctx: &mut Self::CTX,
+    ) -> u16 {
+        // the upstream failed mid-response, an error body would corrupt what the client got
+        if let Some(response) = session.response_written() {
+            error!(
+                "Request {} failed after the response was sent: {}",
+                ctx.request_id, e
+            );
+            return response.status.as_u16();
+        }
+        let Some(s3_error) = ctx.s3_error.take().or_else(|| S3Error::from_proxy_error(e)) else {
+            // the client is gone, nobody to answer
+            return 0;
+        };
+        if let Err(write_error) = s3_error.respond(session, &ctx.request_id).await {
+            error!("Failed to send error response: {}", write_error);
+        }
+        s3_error.status
+    }
 }
 
 pub fn init_tracing() {
@@ -324,30 +1466,79 @@ pub fn run_server(py: Python, run_args: &ProxyServerConfig) {
         run_args.port
     );
 
-    match run_args.bucket_creds_fetcher {
-        Some(ref fetcher) => {
-            info!("Bucket creds fetcher provided: {:?}", fetcher);
-            let _d = get_api_key_for_bucket(py, fetcher, "bucket01".to_string());
-        }
-        None => {
-            info!("No bucket creds fetcher provided");
-        }
-    }
-
     let cosmap = parse_cos_map(py, &run_args.cos_map).unwrap();
 
+    let presign_keystore = run_args.presign_secret.as_ref().map(|secret| {
+        info!("Presign secret provided; honouring proxy-issued presigned urls");
+        HashMap::from([(PRESIGN_ACCESS_KEY.to_string(), secret.clone())])
+    });
+
     let mut my_server = Server::new(None).unwrap();
     my_server.bootstrap();
 
-    let validator = run_args.validator.as_ref().map(|v| v.clone_ref(py));
+    let callback_pool = Arc::new(build_callback_pool(run_args).unwrap());
+    let mut validator = build_validator(py, run_args, &callback_pool).unwrap();
+    if let Some(cache) = &run_args.decision_cache {
+        info!("Caching validator decisions");
+        let cache = cache.borrow(py).clone();
+        validator = Arc::new(CachingValidator::new(cache, validator));
+    }
+    let policy = build_policy(py, run_args).unwrap();
+
+    let bucket_resolver = build_bucket_resolver(py, run_args, &callback_pool).unwrap();
+    if bucket_resolver.is_some() {
+        info!("Bucket resolver provided; resolving buckets missing from the cos map");
+    }
+
+    let creds_fetcher = build_creds_fetcher(py, run_args, &callback_pool).unwrap();
+    match &run_args.bucket_creds_fetcher {
+        Some(fetcher) => info!("Bucket creds fetcher provided: {:?}", fetcher),
+        None => info!("No bucket creds fetcher provided"),
+    }
+
+    let hmac_keystore = match run_args.hmac_keystore {
+        Some(ref keystore) => {
+            let keystore = parse_hmac_keystore(py, keystore).unwrap();
+            info!(
+                "HMAC keystore provided with {} access keys; verifying request signatures",
+                keystore.len()
+            );
+            Some(keystore)
+        }
+        None => {
+            info!("No HMAC keystore provided; request signatures are not verified");
+            None
+        }
+    };
+
+    let secrets_cache = build_secrets_cache(run_args).unwrap();
+    let providers = ProviderCache::new(&cosmap, callback_pool.clone());
+    let token_refresher = TokenRefresher::new(secrets_cache.clone(), providers.configured());
+    if !token_refresher.is_empty() {
+        my_server.add_service(background_service("token refresher", token_refresher));
+    }
+    my_server.add_service(background_service(
+        "secret file watcher",
+        SecretFileWatcher::new(secrets_cache.clone()),
+    ));
+    my_server.add_service(background_service(
+        "token store flusher",
+        TokenStoreFlusher::new(secrets_cache.clone()),
+    ));
 
     let mut my_proxy = pingora::proxy::http_proxy_service(
         &my_server.configuration,
         MyProxy {
             cos_endpoint: "s3.eu-de.cloud-object-storage.appdomain.cloud".to_string(),
             cos_mapping: cosmap,
-            secrets_cache: SecretsCache::new(),
+            secrets_cache,
             validator,
+            hmac_keystore,
+            presign_keystore,
+            policy,
+            creds_fetcher,
+            bucket_resolver,
+            providers,
         },
     );
     my_proxy.add_tcp("0.0.0.0:6190");
@@ -360,27 +1551,11 @@ pub fn run_server(py: Python, run_args: &ProxyServerConfig) {
     info!("server running ...");
 }
 
-fn get_api_key_for_bucket(py: Python, callback: &PyObject, bucket: String) -> PyResult<()> {
-    match callback.call1(py, (bucket,)) {
-        Ok(result) => {
-            let content = result.extract::<String>(py)?;
-            info!("Callback returned: {}...", content.chars().take(4).collect::<String>());
-            Ok(())
-        }
-        Err(err) => {
-            error!("Python callback raised an exception: {:?}", err);
-            return Err(PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(
-                "Failed to call callback due to an inner Python exception",
-            ));
-        }
-    }
-}
-
 #[pyfunction]
 pub fn start_server(py: Python, run_args: &ProxyServerConfig) -> PyResult<()> {
     dotenv().ok();
 
-    run_server(py, &run_args);
+    run_server(py, run_args);
 
     Ok(())
 }
@@ -389,5 +1564,526 @@ pub fn start_server(py: Python, run_args: &ProxyServerConfig) -> PyResult<()> {
 fn object_storage_proxy(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(start_server, m)?)?;
     m.add_class::<ProxyServerConfig>()?;
+    m.add_class::<AuthDecision>()?;
+    m.add_class::<DecisionCache>()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use credentials::secrets_proxy::now_secs;
+
+    fn ctx(secrets_cache: &SecretsCache, token_key: &str, bucket: Option<&str>) -> MyCtx {
+        MyCtx {
+            bucket_config: None,
+            secrets_cache: secrets_cache.clone(),
+            aws_chunked: None,
+            upstream_headers: HashMap::new(),
+            bucket_override: None,
+            request_id: new_request_id(),
+            s3_error: None,
+            bearer_token: Some((token_key.to_string(), "token1".to_string())),
+            auth_retried: false,
+            fetched_creds_bucket: bucket.map(str::to_string),
+            payload_verifier: None,
+            error_response: None,
+        }
+    }
+
+    /// A creds fetcher answering with a fixed API key, and the list of buckets it was
+    /// called for.
+    fn creds_fetcher() -> (BucketCredsFetcher, Py<PyAny>) {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let calls = pyo3::types::PyList::empty(py);
+            let callback = py
+                .eval(
+                    c"lambda calls: lambda bucket: calls.append(bucket) or 'api-key'",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .call1((&calls,))
+                .unwrap();
+            let pool = Arc::new(CallbackPool::new(2, 10, Duration::from_secs(5)));
+            (
+                BucketCredsFetcher::new(
+                    callback.unbind(),
+                    pool,
+                    Duration::from_secs(300),
+                    Duration::from_secs(30),
+                ),
+                calls.into_any().unbind(),
+            )
+        })
+    }
+
+    fn call_count(calls: &Py<PyAny>) -> usize {
+        Python::with_gil(|py| calls.bind(py).len().unwrap())
+    }
+
+    #[test]
+    fn test_validation_outcome() {
+        let denied = AuthDecision::from(false);
+        assert_eq!(
+            validation_outcome(Ok(denied.clone()), true, "bucket1"),
+            Ok(denied)
+        );
+        // shed calls are never let through
+        assert_eq!(
+            validation_outcome(Err(CallbackError::Overloaded), true, "bucket1"),
+            Err(S3Error::slow_down())
+        );
+        assert_eq!(
+            validation_outcome(Err(CallbackError::TimedOut), false, "bucket1"),
+            Err(S3Error::service_unavailable())
+        );
+        assert_eq!(
+            validation_outcome(Err(CallbackError::TimedOut), true, "bucket1"),
+            Ok(AuthDecision::from(true))
+        );
+        assert_eq!(
+            validation_outcome(
+                Err(CallbackError::Failed("boom".to_string())),
+                true,
+                "bucket1"
+            ),
+            Ok(AuthDecision::from(true))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejected_credentials_are_dropped_once() {
+        let cache = SecretsCache::new();
+        let (fetcher, calls) = creds_fetcher();
+        fetcher.get("bucket1").await.unwrap();
+        let expiration = now_secs() + 3600;
+        cache
+            .insert("key1".to_string(), "token1".to_string(), expiration)
+            .await;
+
+        let mut ctx = ctx(&cache, "key1", Some("bucket1"));
+        assert!(!drop_rejected_credentials(200, None, Some(&fetcher), &mut ctx).await);
+        // denied by a policy rather than for the token
+        assert!(
+            !drop_rejected_credentials(403, Some("AccessDenied"), Some(&fetcher), &mut ctx).await
+        );
+        assert!(!drop_rejected_credentials(403, None, Some(&fetcher), &mut ctx).await);
+        assert!(cache.peek("key1").await.is_some());
+
+        assert!(
+            drop_rejected_credentials(403, Some("InvalidToken"), Some(&fetcher), &mut ctx).await
+        );
+        assert!(cache.peek("key1").await.is_none());
+        // the API key is fetched again as well
+        fetcher.get("bucket1").await.unwrap();
+        assert_eq!(call_count(&calls), 2);
+
+        // the retry is not retried again
+        ctx.bearer_token = Some(("key1".to_string(), "token1".to_string()));
+        assert!(!drop_rejected_credentials(401, None, Some(&fetcher), &mut ctx).await);
+    }
+
+    #[tokio::test]
+    async fn test_credentials_renewed_meanwhile_are_kept() {
+        let cache = SecretsCache::new();
+        let (fetcher, calls) = creds_fetcher();
+        fetcher.get("bucket2").await.unwrap();
+        cache
+            .insert("key2".to_string(), "token1".to_string(), now_secs() + 3600)
+            .await;
+
+        let mut first = ctx(&cache, "key2", Some("bucket2"));
+        assert!(drop_rejected_credentials(401, None, Some(&fetcher), &mut first).await);
+        fetcher.get("bucket2").await.unwrap();
+        cache
+            .insert("key2".to_string(), "token2".to_string(), now_secs() + 3600)
+            .await;
+
+        // a concurrent request rejected with the old token retries with the new one
+        let mut second = ctx(&cache, "key2", Some("bucket2"));
+        assert!(drop_rejected_credentials(401, None, Some(&fetcher), &mut second).await);
+        assert_eq!(cache.peek("key2").await.unwrap().get_value(), "token2");
+        fetcher.get("bucket2").await.unwrap();
+        assert_eq!(call_count(&calls), 2);
+    }
+
+    /// A proxy for `cos_map` that lets every request through.
+    fn proxy(cos_map: HashMap<String, CosMapItem>) -> MyProxy {
+        let pool = Arc::new(CallbackPool::new(2, 10, Duration::from_secs(5)));
+        MyProxy {
+            cos_endpoint: "s3.eu-de.cloud-object-storage.appdomain.cloud".to_string(),
+            providers: ProviderCache::new(&cos_map, pool),
+            cos_mapping: cos_map,
+            secrets_cache: SecretsCache::new(),
+            validator: Arc::new(AllowAllValidator),
+            hmac_keystore: None,
+            presign_keystore: None,
+            policy: None,
+            creds_fetcher: None,
+            bucket_resolver: None,
+        }
+    }
+
+    /// Runs `proxy` on a free local port until the test ends, returning its base URL.
+    async fn serve(proxy: MyProxy) -> String {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let conf = Arc::new(pingora::server::configuration::ServerConf::default());
+        let mut service = pingora::proxy::http_proxy_service(&conf, proxy);
+        service.add_tcp(&addr.to_string());
+        let (shutdown, watch) = tokio::sync::watch::channel(false);
+        tokio::spawn(async move {
+            let _shutdown = shutdown;
+            pingora::services::Service::start_service(&mut service, None, watch).await;
+        });
+        for _ in 0..50 {
+            if tokio::net::TcpStream::connect(addr).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        format!("http://{}", addr)
+    }
+
+    /// How many `verb` requests `server` got.
+    async fn received(server: &wiremock::MockServer, verb: &str) -> usize {
+        let requests = server.received_requests().await.unwrap();
+        requests
+            .iter()
+            .filter(|request| request.method.as_str() == verb)
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_rejected_token_is_renewed_and_idempotent_requests_retried() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let iam = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                format!(
+                    r#"{{"access_token": "token1", "expires_in": 3600, "expiration": {}}}"#,
+                    now_secs() + 3600
+                ),
+                "application/json",
+            ))
+            .mount(&iam)
+            .await;
+
+        let upstream = MockServer::start().await;
+        let rejected = || {
+            ResponseTemplate::new(403)
+                .insert_header("x-amz-error-code", "InvalidToken")
+                .set_body_string("<Error><Code>InvalidToken</Code></Error>")
+        };
+        Mock::given(method("GET"))
+            .and(path("/key1"))
+            .respond_with(rejected())
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&upstream)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/key1"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("hello"))
+            .mount(&upstream)
+            .await;
+        Mock::given(method("PUT"))
+            .respond_with(rejected())
+            .mount(&upstream)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(rejected())
+            .mount(&upstream)
+            .await;
+
+        // the error code only in the body, as S3 sends it
+        Mock::given(method("GET"))
+            .and(path("/denied"))
+            .respond_with(
+                ResponseTemplate::new(403)
+                    .set_body_string("<Error><Code>AccessDenied</Code></Error>"),
+            )
+            .mount(&upstream)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/key2"))
+            .respond_with(
+                ResponseTemplate::new(403)
+                    .set_body_string("<Error><Code>ExpiredToken</Code></Error>"),
+            )
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&upstream)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/key2"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&upstream)
+            .await;
+
+        let upstream_addr = upstream.address();
+        let bucket = |api_key: &str| CosMapItem {
+            host: upstream_addr.ip().to_string(),
+            port: upstream_addr.port(),
+            tls: false,
+            api_key: Some(SecretSource::parse(api_key)),
+            iam_url: Some(iam.uri()),
+            ..Default::default()
+        };
+        let cos_map = HashMap::from([
+            ("bucket1".to_string(), bucket("api-key1")),
+            ("bucket2".to_string(), bucket("api-key2")),
+        ]);
+        let base = serve(proxy(cos_map)).await;
+        let client = reqwest::Client::new();
+        let response = client
+            .get(format!("{}/bucket1/key1", base))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "hello");
+        assert_eq!(received(&upstream, "GET").await, 2);
+        assert_eq!(received(&iam, "POST").await, 2);
+
+        for verb in [http::Method::PUT, http::Method::POST] {
+            let response = client
+                .request(verb.clone(), format!("{}/bucket1/key1", base))
+                .body("data")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 403);
+            assert_eq!(received(&upstream, verb.as_str()).await, 1);
+        }
+        // nor is the token renewed again right after it was
+        assert_eq!(received(&iam, "POST").await, 2);
+
+        // a code found in the body is too late for a retry, but the token is dropped for
+        // the next request; one denied by a policy is kept
+        let get = |object: &str| client.get(format!("{}/bucket2/{}", base, object)).send();
+        assert_eq!(get("denied").await.unwrap().status(), 403);
+        assert_eq!(received(&iam, "POST").await, 3);
+        assert_eq!(get("key2").await.unwrap().status(), 403);
+        assert_eq!(get("key2").await.unwrap().status(), 200);
+        assert_eq!(received(&iam, "POST").await, 4);
+    }
+
+    #[tokio::test]
+    async fn test_body_must_match_its_content_sha256() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let upstream = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&upstream)
+            .await;
+        let cos_map = HashMap::from([(
+            "bucket1".to_string(),
+            CosMapItem {
+                host: upstream.address().ip().to_string(),
+                port: upstream.address().port(),
+                tls: false,
+                token: Some(SecretSource::parse("token1")),
+                ..Default::default()
+            },
+        )]);
+        let base = serve(proxy(cos_map)).await;
+        let put = |body: &'static str| {
+            reqwest::Client::new()
+                .put(format!("{}/bucket1/key1", base))
+                .header(
+                    "x-amz-content-sha256",
+                    credentials::signer::sha256_hex(b"data"),
+                )
+                .body(body)
+                .send()
+        };
+
+        assert_eq!(put("data").await.unwrap().status(), 200);
+        let response = put("evil").await.unwrap();
+        assert_eq!(response.status(), 400);
+        assert!(
+            response
+                .text()
+                .await
+                .unwrap()
+                .contains("<Code>XAmzContentSHA256Mismatch</Code>")
+        );
+        assert_eq!(received(&upstream, "PUT").await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_aws_chunked_uploads_are_forwarded_as_plain_payloads() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let upstream = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&upstream)
+            .await;
+        let cos_map = HashMap::from([(
+            "bucket1".to_string(),
+            CosMapItem {
+                host: upstream.address().ip().to_string(),
+                port: upstream.address().port(),
+                tls: false,
+                token: Some(SecretSource::parse("token1")),
+                ..Default::default()
+            },
+        )]);
+        let base = serve(proxy(cos_map)).await;
+
+        let plain = "4\r\ndata\r\n0\r\n\r\n";
+        let with_trailer = "4\r\ndata\r\n0\r\nx-amz-checksum-crc32:rfPzYw==\r\n\r\n";
+        for (content_sha256, body) in [
+            ("STREAMING-UNSIGNED-PAYLOAD", plain),
+            ("STREAMING-UNSIGNED-PAYLOAD-TRAILER", with_trailer),
+        ] {
+            let mut request = reqwest::Client::new()
+                .put(format!("{}/bucket1/key1", base))
+                .header("x-amz-content-sha256", content_sha256)
+                .header("content-encoding", "aws-chunked,gzip")
+                .header("x-amz-decoded-content-length", "4");
+            if body == with_trailer {
+                request = request
+                    .header("x-amz-trailer", "x-amz-checksum-crc32")
+                    .header("x-amz-sdk-checksum-algorithm", "CRC32");
+            }
+            assert_eq!(request.body(body).send().await.unwrap().status(), 200);
+        }
+
+        let requests = upstream.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in requests {
+            assert_eq!(request.body, b"data");
+            let header = |name: &str| {
+                request
+                    .headers
+                    .get(name)
+                    .map(|value| value.to_str().unwrap().to_string())
+            };
+            assert_eq!(header("content-length").as_deref(), Some("4"));
+            assert_eq!(header("content-encoding").as_deref(), Some("gzip"));
+            assert_eq!(
+                header("x-amz-content-sha256").as_deref(),
+                Some(UNSIGNED_PAYLOAD)
+            );
+            for dropped in [
+                "x-amz-decoded-content-length",
+                "x-amz-trailer",
+                "x-amz-sdk-checksum-algorithm",
+            ] {
+                assert_eq!(header(dropped), None, "{}", dropped);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_buckets_are_addressed_in_the_configured_style() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let upstream = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&upstream)
+            .await;
+        let host = upstream.address().ip().to_string();
+        let item = |addressing_style| CosMapItem {
+            host: host.clone(),
+            port: upstream.address().port(),
+            tls: false,
+            addressing_style,
+            access_key: Some(SecretSource::parse("AKID")),
+            secret_key: Some(SecretSource::parse("secret")),
+            ..Default::default()
+        };
+        let cos_map = HashMap::from([
+            ("virtual1".to_string(), item(AddressingStyle::Virtual)),
+            ("path1".to_string(), item(AddressingStyle::Path)),
+        ]);
+        let base = serve(proxy(cos_map)).await;
+
+        for path in ["virtual1/dir/key1", "path1/dir/key1", "path1"] {
+            let response = reqwest::get(format!("{}/{}?list-type=2", base, path))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200);
+        }
+
+        let requests = upstream.received_requests().await.unwrap();
+        let seen: Vec<_> = requests
+            .iter()
+            .map(|request| {
+                let header = |name| request.headers.get(name).unwrap().to_str().unwrap();
+                assert!(header("authorization").starts_with("AWS4-HMAC-SHA256 Credential=AKID/"));
+                (header("host").to_string(), request.url.path().to_string())
+            })
+            .collect();
+        assert_eq!(
+            seen,
+            vec![
+                (format!("virtual1.{}", host), "/dir/key1".to_string()),
+                (host.clone(), "/path1/dir/key1".to_string()),
+                (host.clone(), "/path1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_cos_map_tls() {
+        pyo3::prepare_freethreaded_python();
+        let cos_map = Python::with_gil(|py| {
+            let cos_map = py
+                .eval(
+                    c"{'cos': {'host': 's3.example.com', 'port': 443}, 'minio': {'host': 'minio.local', 'port': 9000, 'tls': False, 'addressing_style': 'path', 'verify_cert': False}}",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .unbind();
+            parse_cos_map(py, &cos_map).unwrap()
+        });
+        assert!(cos_map["cos"].tls);
+        assert!(cos_map["cos"].verify_cert);
+        assert_eq!(cos_map["cos"].addressing_style, AddressingStyle::Virtual);
+        assert!(!cos_map["minio"].tls);
+        assert!(!cos_map["minio"].verify_cert);
+        assert_eq!(cos_map["minio"].addressing_style, AddressingStyle::Path);
+
+        let invalid = Python::with_gil(|py| {
+            let cos_map = py
+                .eval(
+                    c"{'minio': {'host': 'minio.local', 'port': 9000, 'addressing_style': 'dns'}}",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .unbind();
+            parse_cos_map(py, &cos_map)
+        });
+        assert!(invalid.is_err());
+
+        let tuples = Python::with_gil(|py| {
+            let tuples = py
+                .eval(
+                    c"[('cos', 's3.example.com', 443, 'instance1', None)]",
+                    None,
+                    None,
+                )
+                .unwrap()
+                .unbind();
+            parse_cos_map(py, &tuples).unwrap()
+        });
+        assert!(tuples["cos"].tls);
+    }
+}
@@ -0,0 +1,418 @@
+use bytes::Bytes;
+use nom::{
+    IResult, Parser,
+    bytes::complete::tag,
+    character::complete::hex_digit1,
+    combinator::{map_res, opt},
+    sequence::preceded,
+};
+use sha2::{Digest, Sha256};
+
+use crate::credentials::signer::{ChunkSigner, signatures_match};
+
+/// Largest chunk header we are willing to buffer while waiting for its line end
+const MAX_CHUNK_HEADER_LEN: usize = 4096;
+/// Largest signed chunk we are willing to hold back until its signature is verified
+const MAX_SIGNED_CHUNK_LEN: usize = 16 * 1024 * 1024;
+/// Trailing header carrying the signature of the other trailing headers
+const TRAILER_SIGNATURE_HEADER: &str = "x-amz-trailer-signature";
+
+/// Parses an `aws-chunked` chunk header: `<hex size>[;chunk-signature=<signature>]`.
+pub fn parse_chunk_header(input: &str) -> IResult<&str, (usize, Option<&str>)> {
+    (
+        map_res(hex_digit1, |size| usize::from_str_radix(size, 16)),
+        opt(preceded(tag(";chunk-signature="), hex_digit1)),
+    )
+        .parse(input)
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Header,
+    Data {
+        remaining: usize,
+        signature: Option<String>,
+    },
+    DataEnd {
+        signature: Option<String>,
+    },
+    Trailer,
+    Done,
+}
+
+/// Incrementally decodes an `aws-chunked` request body into the plain payload, verifying
+/// the chunk signatures when a `ChunkSigner` is given. Signed chunks are only released once
+/// their signature checks out, so a forged chunk never reaches the upstream. Trailing
+/// headers are dropped, after verifying their signature for uploads with a signed trailer.
+pub struct AwsChunkedDecoder {
+    buffer: Vec<u8>,
+    state: State,
+    hasher: Sha256,
+    signer: Option<ChunkSigner>,
+    /// data of the current chunk, held back until its signature is verified
+    pending: Vec<u8>,
+    signed_trailer: bool,
+    /// canonical form of the trailing headers read so far, `name:value\n` each
+    trailer: String,
+}
+
+impl AwsChunkedDecoder {
+    pub fn new(signer: Option<ChunkSigner>) -> Self {
+        AwsChunkedDecoder {
+            buffer: Vec::new(),
+            state: State::Header,
+            hasher: Sha256::new(),
+            signer,
+            pending: Vec::new(),
+            signed_trailer: false,
+            trailer: String::new(),
+        }
+    }
+
+    /// A decoder for `STREAMING-AWS4-HMAC-SHA256-PAYLOAD-TRAILER` uploads, which also
+    /// requires a valid `x-amz-trailer-signature` after the trailing headers.
+    pub fn with_signed_trailer(signer: ChunkSigner) -> Self {
+        AwsChunkedDecoder {
+            signed_trailer: true,
+            ..Self::new(Some(signer))
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    fn take_line(&mut self) -> Result<Option<String>, String> {
+        match self.buffer.windows(2).position(|w| w == b"\r\n") {
+            Some(pos) => {
+                let line = String::from_utf8_lossy(&self.buffer[..pos]).into_owned();
+                self.buffer.drain(..pos + 2);
+                Ok(Some(line))
+            }
+            None if self.buffer.len() > MAX_CHUNK_HEADER_LEN => {
+                Err("aws-chunked header line too long".to_string())
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn verify_chunk(&mut self, signature: Option<String>) -> Result<(), String> {
+        let chunk_hash = hex::encode(std::mem::take(&mut self.hasher).finalize());
+        let Some(signer) = self.signer.as_mut() else {
+            return Ok(());
+        };
+        let Some(signature) = signature else {
+            return Err("aws-chunked chunk without signature".to_string());
+        };
+        if !signatures_match(&signer.next_signature(&chunk_hash), &signature) {
+            return Err("aws-chunked chunk signature does not match".to_string());
+        }
+        Ok(())
+    }
+
+    /// Handles a line of the trailer. Returns whether it was the empty line ending the body.
+    fn trailer_line(&mut self, line: &str) -> Result<bool, String> {
+        if line.is_empty() {
+            if self.signed_trailer {
+                return Err("aws-chunked trailer without signature".to_string());
+            }
+            return Ok(true);
+        }
+        if !self.signed_trailer {
+            return Ok(false);
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("invalid aws-chunked trailer: {}", line))?;
+        let name = name.trim().to_ascii_lowercase();
+        if name != TRAILER_SIGNATURE_HEADER {
+            self.trailer
+                .push_str(&format!("{}:{}\n", name, value.trim()));
+            return Ok(false);
+        }
+
+        let trailer_hash = hex::encode(Sha256::digest(self.trailer.as_bytes()));
+        let signer = self
+            .signer
+            .as_mut()
+            .expect("signed trailers come with a signer");
+        if !signatures_match(&signer.trailer_signature(&trailer_hash), value.trim()) {
+            return Err("aws-chunked trailer signature does not match".to_string());
+        }
+        // the empty line ending the body is optional after the signature
+        self.signed_trailer = false;
+        Ok(false)
+    }
+
+    /// Feeds the next piece of the encoded body and returns the payload decoded so far.
+    pub fn decode(&mut self, input: &[u8]) -> Result<Bytes, String> {
+        self.buffer.extend_from_slice(input);
+        let mut output = Vec::with_capacity(input.len());
+
+        loop {
+            match &mut self.state {
+                State::Header => {
+                    let Some(line) = self.take_line()? else {
+                        break;
+                    };
+                    let (_, (size, signature)) = parse_chunk_header(&line)
+                        .map_err(|_| format!("invalid aws-chunked header: {}", line))?;
+                    let signature = signature.map(str::to_string);
+                    if self.signer.is_some() && size > MAX_SIGNED_CHUNK_LEN {
+                        return Err(format!("aws-chunked chunk too large: {} bytes", size));
+                    }
+                    if size == 0 {
+                        self.verify_chunk(signature)?;
+                        self.state = State::Trailer;
+                    } else {
+                        self.state = State::Data {
+                            remaining: size,
+                            signature,
+                        };
+                    }
+                }
+                State::Data {
+                    remaining,
+                    signature,
+                } => {
+                    if self.buffer.is_empty() {
+                        break;
+                    }
+                    let n = (*remaining).min(self.buffer.len());
+                    self.hasher.update(&self.buffer[..n]);
+                    let data = self.buffer.drain(..n);
+                    if self.signer.is_some() {
+                        self.pending.extend(data);
+                    } else {
+                        output.extend(data);
+                    }
+                    *remaining -= n;
+                    if *remaining == 0 {
+                        self.state = State::DataEnd {
+                            signature: signature.take(),
+                        };
+                    }
+                }
+                State::DataEnd { signature } => {
+                    if self.buffer.len() < 2 {
+                        break;
+                    }
+                    if &self.buffer[..2] != b"\r\n" {
+                        return Err("aws-chunked chunk not terminated by CRLF".to_string());
+                    }
+                    self.buffer.drain(..2);
+                    let signature = signature.take();
+                    self.verify_chunk(signature)?;
+                    output.append(&mut self.pending);
+                    self.state = State::Header;
+                }
+                State::Trailer => {
+                    let Some(line) = self.take_line()? else {
+                        break;
+                    };
+                    if self.trailer_line(&line)? {
+                        self.state = State::Done;
+                    }
+                }
+                State::Done => {
+                    self.buffer.clear();
+                    break;
+                }
+            }
+        }
+
+        Ok(Bytes::from(output))
+    }
+
+    /// Checks that the body ended on a chunk boundary, after the final zero-length chunk
+    /// and, for signed trailers, after the trailer signature.
+    pub fn finish(&mut self) -> Result<(), String> {
+        match self.state {
+            State::Done => Ok(()),
+            // some clients end the body right after the final chunk without the empty line
+            State::Trailer if self.buffer.is_empty() && !self.signed_trailer => {
+                self.state = State::Done;
+                Ok(())
+            }
+            State::Trailer if self.buffer.is_empty() => {
+                Err("aws-chunked trailer without signature".to_string())
+            }
+            _ => Err("aws-chunked body ended prematurely".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::signer::{sha256_hex, signing_key};
+
+    const SEED_SIGNATURE: &str = "4f232c4386841ef735655705268965c44a0e4690baa4adea153f7db9fa80a0a9";
+
+    fn aws_example_signer() -> ChunkSigner {
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+            "20130524",
+            "us-east-1",
+            "s3",
+        );
+        ChunkSigner::new(
+            key,
+            "20130524T000000Z",
+            "20130524/us-east-1/s3/aws4_request",
+            SEED_SIGNATURE,
+        )
+    }
+
+    fn encode(chunks: &[&[u8]], mut signer: Option<ChunkSigner>) -> Vec<u8> {
+        let mut body = Vec::new();
+        for chunk in chunks.iter().chain(std::iter::once(&&b""[..])) {
+            let header = match signer.as_mut() {
+                Some(signer) => format!(
+                    "{:x};chunk-signature={}\r\n",
+                    chunk.len(),
+                    signer.next_signature(&sha256_hex(chunk))
+                ),
+                None => format!("{:x}\r\n", chunk.len()),
+            };
+            body.extend_from_slice(header.as_bytes());
+            body.extend_from_slice(chunk);
+            body.extend_from_slice(b"\r\n");
+        }
+        body
+    }
+
+    #[test]
+    fn test_parse_chunk_header() {
+        assert_eq!(
+            parse_chunk_header("10000;chunk-signature=ad80c730"),
+            Ok(("", (65536, Some("ad80c730"))))
+        );
+        assert_eq!(parse_chunk_header("400"), Ok(("", (1024, None))));
+        assert!(parse_chunk_header("zz;chunk-signature=ab").is_err());
+    }
+
+    #[test]
+    fn test_decode_signed_body_aws_example() {
+        let first = vec![b'a'; 65536];
+        let second = vec![b'a'; 1024];
+        let body = encode(&[&first, &second], Some(aws_example_signer()));
+        assert!(body.starts_with(
+            b"10000;chunk-signature=ad80c730a21e5b8d04586a2213dd63b9a0e99e0e2307b0ade35a65485a288648\r\n"
+        ));
+
+        let mut decoder = AwsChunkedDecoder::new(Some(aws_example_signer()));
+        let mut decoded = Vec::new();
+        // feed in awkward pieces to exercise the buffering
+        for piece in body.chunks(1000) {
+            decoded.extend_from_slice(&decoder.decode(piece).unwrap());
+        }
+        assert!(decoder.finish().is_ok());
+        assert_eq!(decoded.len(), 65536 + 1024);
+        assert_eq!(sha256_hex(&decoded), sha256_hex(&[b'a'; 66560]));
+    }
+
+    #[test]
+    fn test_decode_rejects_tampered_chunk() {
+        let mut body = encode(&[b"hello world"], Some(aws_example_signer()));
+        let pos = body.windows(5).position(|w| w == b"hello").unwrap();
+        body[pos] = b'j';
+
+        let mut decoder = AwsChunkedDecoder::new(Some(aws_example_signer()));
+        assert!(decoder.decode(&body).is_err());
+    }
+
+    #[test]
+    fn test_decode_holds_back_chunk_until_verified() {
+        let body = encode(&[b"hello world"], Some(aws_example_signer()));
+        let end = body.windows(5).position(|w| w == b"world").unwrap() + 5;
+
+        let mut decoder = AwsChunkedDecoder::new(Some(aws_example_signer()));
+        assert!(decoder.decode(&body[..end]).unwrap().is_empty());
+        assert_eq!(
+            decoder.decode(&body[end..]).unwrap(),
+            Bytes::from("hello world")
+        );
+        assert!(decoder.finish().is_ok());
+
+        // a forged chunk is rejected without any of it coming out
+        let mut forged = body.clone();
+        forged[end - 1] = b'D';
+        let mut decoder = AwsChunkedDecoder::new(Some(aws_example_signer()));
+        assert!(decoder.decode(&forged[..end]).unwrap().is_empty());
+        assert!(decoder.decode(&forged[end..]).is_err());
+    }
+
+    fn with_signed_trailer(mut body: Vec<u8>, trailer: &str, signer: &mut ChunkSigner) -> Vec<u8> {
+        body.truncate(body.len() - 2);
+        let signature = signer.trailer_signature(&sha256_hex(format!("{}\n", trailer).as_bytes()));
+        body.extend_from_slice(
+            format!(
+                "{}\r\nx-amz-trailer-signature:{}\r\n\r\n",
+                trailer, signature
+            )
+            .as_bytes(),
+        );
+        body
+    }
+
+    #[test]
+    fn test_decode_signed_trailer() {
+        let trailer = "x-amz-checksum-crc32:DUoRhQ==";
+        // replays the chunk signatures to get at the one the trailer signature chains on
+        let mut signer = aws_example_signer();
+        signer.next_signature(&sha256_hex(b"hello world"));
+        signer.next_signature(&sha256_hex(b""));
+        let body = with_signed_trailer(
+            encode(&[b"hello world"], Some(aws_example_signer())),
+            trailer,
+            &mut signer.clone(),
+        );
+
+        let mut decoder = AwsChunkedDecoder::with_signed_trailer(aws_example_signer());
+        assert_eq!(decoder.decode(&body).unwrap(), Bytes::from("hello world"));
+        assert!(decoder.finish().is_ok());
+
+        let tampered = with_signed_trailer(
+            encode(&[b"hello world"], Some(aws_example_signer())),
+            trailer,
+            &mut signer,
+        );
+        let tampered = String::from_utf8(tampered)
+            .unwrap()
+            .replace("DUoRhQ==", "AAAAAA==");
+        let mut decoder = AwsChunkedDecoder::with_signed_trailer(aws_example_signer());
+        assert!(decoder.decode(tampered.as_bytes()).is_err());
+
+        // the signature cannot be left out either
+        let unsigned = encode(&[b"hello world"], Some(aws_example_signer()));
+        let mut decoder = AwsChunkedDecoder::with_signed_trailer(aws_example_signer());
+        assert!(decoder.decode(&unsigned).is_err());
+    }
+
+    #[test]
+    fn test_decode_requires_signatures_when_verifying() {
+        let body = encode(&[b"hello world"], None);
+        let mut decoder = AwsChunkedDecoder::new(Some(aws_example_signer()));
+        assert!(decoder.decode(&body).is_err());
+    }
+
+    #[test]
+    fn test_decode_unsigned_with_trailer() {
+        let mut body = encode(&[b"hello ", b"world"], None);
+        body.truncate(body.len() - 2);
+        body.extend_from_slice(b"x-amz-checksum-crc32:DUoRhQ==\r\n\r\n");
+
+        let mut decoder = AwsChunkedDecoder::new(None);
+        assert_eq!(decoder.decode(&body).unwrap(), Bytes::from("hello world"));
+        assert!(decoder.finish().is_ok());
+    }
+
+    #[test]
+    fn test_decode_premature_end() {
+        let body = encode(&[b"hello world"], None);
+        let mut decoder = AwsChunkedDecoder::new(None);
+        decoder.decode(&body[..10]).unwrap();
+        assert!(decoder.finish().is_err());
+    }
+}
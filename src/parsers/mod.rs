@@ -1,2 +1,3 @@
+pub mod aws_chunked;
 pub mod credentials;
 pub mod path;
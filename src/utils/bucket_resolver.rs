@@ -0,0 +1,166 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use pyo3::prelude::*;
+use tracing::info;
+
+use crate::CosMapItem;
+use crate::utils::python::{CallbackError, CallbackPool};
+use crate::utils::ttl_cache::TtlCache;
+
+/// Looks up the configuration of buckets that are not in the static `cos_map`.
+#[async_trait]
+pub trait BucketResolver: Send + Sync + std::fmt::Debug {
+    /// `None` when the bucket is unknown.
+    async fn resolve(&self, bucket: &str) -> Result<Option<CosMapItem>, CallbackError>;
+}
+
+/// Calls the Python `bucket_resolver` callback with the bucket name; it returns a dict
+/// with the `cos_map` fields, or `None` for unknown buckets.
+#[derive(Debug)]
+pub struct PythonBucketResolver {
+    callback: Arc<PyObject>,
+    pool: Arc<CallbackPool>,
+}
+
+impl PythonBucketResolver {
+    pub fn new(callback: PyObject, pool: Arc<CallbackPool>) -> Self {
+        PythonBucketResolver {
+            callback: Arc::new(callback),
+            pool,
+        }
+    }
+}
+
+#[async_trait]
+impl BucketResolver for PythonBucketResolver {
+    async fn resolve(&self, bucket: &str) -> Result<Option<CosMapItem>, CallbackError> {
+        let item = self
+            .pool
+            .call::<_, Option<CosMapItem>>(&self.callback, (bucket.to_string(),))
+            .await?;
+        Ok(item.map(|item| item.for_bucket(bucket)))
+    }
+}
+
+/// Remembers what another resolver returned: known buckets for `ttl`, unknown ones for
+/// `negative_ttl`. Failed lookups are not cached.
+#[derive(Debug)]
+pub struct CachedResolver {
+    inner: Arc<dyn BucketResolver>,
+    cache: TtlCache<CosMapItem, CallbackError>,
+}
+
+impl CachedResolver {
+    pub fn new(inner: Arc<dyn BucketResolver>, ttl: Duration, negative_ttl: Duration) -> Self {
+        CachedResolver {
+            inner,
+            cache: TtlCache::new(ttl, negative_ttl),
+        }
+    }
+
+    pub fn invalidate(&self, bucket: &str) {
+        self.cache.invalidate(bucket);
+    }
+}
+
+#[async_trait]
+impl BucketResolver for CachedResolver {
+    async fn resolve(&self, bucket: &str) -> Result<Option<CosMapItem>, CallbackError> {
+        self.cache
+            .get_or_lookup(bucket, || async {
+                let config = self.inner.resolve(bucket).await?;
+                match &config {
+                    Some(config) => info!(
+                        "Resolved bucket {} to {}:{}",
+                        bucket, config.host, config.port
+                    ),
+                    None => info!("Bucket {} is unknown to the resolver", bucket),
+                }
+                Ok(config)
+            })
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::credentials::secret_source::SecretSource;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Default)]
+    struct CountingResolver {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl BucketResolver for CountingResolver {
+        async fn resolve(&self, bucket: &str) -> Result<Option<CosMapItem>, CallbackError> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            // let concurrent lookups overlap
+            tokio::task::yield_now().await;
+            Ok((bucket == "known").then(|| CosMapItem {
+                host: "s3.eu-de.cloud-object-storage.appdomain.cloud".to_string(),
+                port: 443,
+                instance: "instance1".to_string(),
+                api_key: Some(SecretSource::parse("apikey")),
+                ..Default::default()
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_resolver() {
+        let counting = Arc::new(CountingResolver::default());
+        let resolver = CachedResolver::new(
+            counting.clone(),
+            Duration::from_secs(300),
+            Duration::from_secs(30),
+        );
+
+        for _ in 0..3 {
+            let config = resolver.resolve("known").await.unwrap().unwrap();
+            assert_eq!(config.port, 443);
+            assert!(resolver.resolve("unknown").await.unwrap().is_none());
+        }
+        assert_eq!(counting.calls.load(Ordering::Relaxed), 2);
+
+        resolver.invalidate("known");
+        resolver.resolve("known").await.unwrap();
+        assert_eq!(counting.calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_cached_resolver_ttls() {
+        let resolver = CachedResolver::new(
+            Arc::new(CountingResolver::default()),
+            Duration::from_secs(300),
+            Duration::from_secs(30),
+        );
+        let now = std::time::Instant::now();
+        resolver.cache.remember("unknown", &None, now);
+        assert_eq!(resolver.cache.cached("unknown", now), Some(None));
+        assert_eq!(
+            resolver
+                .cache
+                .cached("unknown", now + Duration::from_secs(31)),
+            None
+        );
+        assert_eq!(resolver.cache.cached("other", now), None);
+    }
+
+    #[tokio::test]
+    async fn test_burst_for_a_new_bucket_resolves_it_once() {
+        let counting = Arc::new(CountingResolver::default());
+        let resolver = CachedResolver::new(
+            counting.clone(),
+            Duration::from_secs(300),
+            Duration::from_secs(30),
+        );
+        let results = futures::future::join_all((0..20).map(|_| resolver.resolve("known"))).await;
+        assert!(results.iter().all(|config| matches!(config, Ok(Some(_)))));
+        assert_eq!(counting.calls.load(Ordering::Relaxed), 1);
+    }
+}
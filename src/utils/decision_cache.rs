@@ -0,0 +1,447 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use pyo3::prelude::*;
+use tracing::debug;
+
+use crate::utils::operation::S3Operation;
+use crate::utils::python::CallbackError;
+use crate::utils::validator::{AccessRequest, AuthDecision, Validator};
+
+/// Everything a validator is asked about, so that a decision is only reused for the exact
+/// same question: access key, bucket, operation, object key and client address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    access_key: Option<String>,
+    bucket: String,
+    operation: S3Operation,
+    key: Option<String>,
+    client_addr: Option<IpAddr>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    decision: AuthDecision,
+    expires: Instant,
+    generation: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    /// Insertion order for evicting the oldest entries, may hold keys that were replaced
+    /// or removed since; those are recognised by their generation and skipped
+    order: VecDeque<(CacheKey, u64)>,
+    generation: u64,
+}
+
+impl CacheState {
+    fn evict_oldest(&mut self) {
+        while let Some((key, generation)) = self.order.pop_front() {
+            if self
+                .entries
+                .get(&key)
+                .is_some_and(|entry| entry.generation == generation)
+            {
+                self.entries.remove(&key);
+                return;
+            }
+        }
+    }
+
+    fn compact_order(&mut self) {
+        let entries = &self.entries;
+        self.order.retain(|(key, generation)| {
+            entries
+                .get(key)
+                .is_some_and(|entry| entry.generation == *generation)
+        });
+    }
+}
+
+#[derive(Debug)]
+struct DecisionCacheInner {
+    state: Mutex<CacheState>,
+    positive_ttl: Duration,
+    negative_ttl: Duration,
+    max_entries: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Remembers validator decisions per request: allowed requests for `positive_ttl` seconds,
+/// denied ones for `negative_ttl` seconds, at most `max_entries` at a time. Failed
+/// validations are never cached. Decisions are keyed by access key, bucket, operation,
+/// object key and client address, or by the first three only when the validator does not
+/// look at the others (see `CachingValidator`). Pass it to `ProxyServerConfig` as
+/// `decision_cache` and keep a reference to invalidate entries or read its counters.
+#[pyclass(name = "DecisionCache")]
+#[derive(Debug, Clone)]
+pub struct DecisionCache {
+    inner: Arc<DecisionCacheInner>,
+}
+
+impl DecisionCache {
+    pub fn new(positive_ttl: Duration, negative_ttl: Duration, max_entries: usize) -> Self {
+        DecisionCache {
+            inner: Arc::new(DecisionCacheInner {
+                state: Mutex::new(CacheState::default()),
+                positive_ttl,
+                negative_ttl,
+                max_entries,
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    fn key(request: &AccessRequest) -> CacheKey {
+        CacheKey {
+            access_key: request.access_key.map(str::to_string),
+            bucket: request.bucket.to_string(),
+            operation: request.operation,
+            key: request.key.map(str::to_string),
+            client_addr: request.client_addr,
+        }
+    }
+
+    fn get_at(&self, request: &AccessRequest, now: Instant) -> Option<AuthDecision> {
+        let mut state = self.inner.state.lock().unwrap();
+        let key = Self::key(request);
+        let decision = match state.entries.get(&key) {
+            Some(entry) if entry.expires > now => Some(entry.decision.clone()),
+            Some(_) => {
+                state.entries.remove(&key);
+                None
+            }
+            None => None,
+        };
+        let counter = match decision {
+            Some(_) => &self.inner.hits,
+            None => &self.inner.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        decision
+    }
+
+    fn insert_at(&self, request: &AccessRequest, decision: &AuthDecision, now: Instant) {
+        let ttl = if decision.allow {
+            self.inner.positive_ttl
+        } else {
+            self.inner.negative_ttl
+        };
+        if ttl.is_zero() || self.inner.max_entries == 0 {
+            return;
+        }
+
+        let mut state = self.inner.state.lock().unwrap();
+        let key = Self::key(request);
+        if !state.entries.contains_key(&key) {
+            if state.entries.len() >= self.inner.max_entries {
+                state.entries.retain(|_, entry| entry.expires > now);
+            }
+            while state.entries.len() >= self.inner.max_entries {
+                state.evict_oldest();
+            }
+        }
+
+        state.generation += 1;
+        let generation = state.generation;
+        state.entries.insert(
+            key.clone(),
+            CacheEntry {
+                decision: decision.clone(),
+                expires: now + ttl,
+                generation,
+            },
+        );
+        state.order.push_back((key, generation));
+        if state.order.len() > 2 * self.inner.max_entries {
+            state.compact_order();
+        }
+    }
+
+    /// Drops the cached decisions for `access_key` and/or `bucket`, or all of them when
+    /// neither is given. Returns how many were dropped.
+    pub fn invalidate_matching(&self, access_key: Option<&str>, bucket: Option<&str>) -> usize {
+        let mut state = self.inner.state.lock().unwrap();
+        let before = state.entries.len();
+        state.entries.retain(|key, _| {
+            let key_matches = access_key.is_none_or(|k| key.access_key.as_deref() == Some(k));
+            let bucket_matches = bucket.is_none_or(|b| key.bucket == b);
+            !(key_matches && bucket_matches)
+        });
+        let removed = before - state.entries.len();
+        state.compact_order();
+        removed
+    }
+
+    pub fn hit_count(&self) -> u64 {
+        self.inner.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn miss_count(&self) -> u64 {
+        self.inner.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn size(&self) -> usize {
+        self.inner.state.lock().unwrap().entries.len()
+    }
+}
+
+#[pymethods]
+impl DecisionCache {
+    #[new]
+    #[pyo3(signature = (positive_ttl=60.0, negative_ttl=5.0, max_entries=10000))]
+    fn py_new(positive_ttl: f64, negative_ttl: f64, max_entries: usize) -> PyResult<Self> {
+        let ttl = |seconds: f64, name: &str| {
+            Duration::try_from_secs_f64(seconds).map_err(|_| {
+                pyo3::exceptions::PyValueError::new_err(format!(
+                    "{} must be a non-negative number of seconds",
+                    name
+                ))
+            })
+        };
+        Ok(Self::new(
+            ttl(positive_ttl, "positive_ttl")?,
+            ttl(negative_ttl, "negative_ttl")?,
+            max_entries,
+        ))
+    }
+
+    #[pyo3(signature = (access_key=None, bucket=None))]
+    fn invalidate(&self, access_key: Option<&str>, bucket: Option<&str>) -> usize {
+        self.invalidate_matching(access_key, bucket)
+    }
+
+    #[getter]
+    fn hits(&self) -> u64 {
+        self.hit_count()
+    }
+
+    #[getter]
+    fn misses(&self) -> u64 {
+        self.miss_count()
+    }
+
+    fn __len__(&self) -> usize {
+        self.size()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "DecisionCache(size={}, hits={}, misses={})",
+            self.size(),
+            self.hit_count(),
+            self.miss_count()
+        )
+    }
+}
+
+/// Answers from a `DecisionCache` and only asks the wrapped validator on a miss. Unless
+/// the validator's decisions may depend on the object key and client address, these are
+/// left out of the cache key, so that one decision serves every object in a bucket.
+#[derive(Debug)]
+pub struct CachingValidator {
+    cache: DecisionCache,
+    inner: Arc<dyn Validator>,
+}
+
+impl CachingValidator {
+    pub fn new(cache: DecisionCache, inner: Arc<dyn Validator>) -> Self {
+        CachingValidator { cache, inner }
+    }
+
+    /// The request as far as the wrapped validator's decision depends on it.
+    fn cache_key<'a>(&self, request: &AccessRequest<'a>) -> AccessRequest<'a> {
+        if self.inner.uses_key_and_client() {
+            return request.clone();
+        }
+        AccessRequest {
+            key: None,
+            client_addr: None,
+            ..request.clone()
+        }
+    }
+}
+
+#[async_trait]
+impl Validator for CachingValidator {
+    async fn validate(&self, request: &AccessRequest<'_>) -> Result<AuthDecision, CallbackError> {
+        let cache_key = self.cache_key(request);
+        if let Some(decision) = self.cache.get_at(&cache_key, Instant::now()) {
+            debug!(
+                "Decision cache hit for {} on {}",
+                request.operation, request.bucket
+            );
+            return Ok(decision);
+        }
+        let decision = self.inner.validate(request).await?;
+        self.cache.insert_at(&cache_key, &decision, Instant::now());
+        Ok(decision)
+    }
+
+    fn uses_key_and_client(&self) -> bool {
+        self.inner.uses_key_and_client()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request<'a>(access_key: &'a str, bucket: &'a str) -> AccessRequest<'a> {
+        AccessRequest::new(Some(access_key), bucket, S3Operation::GetObject)
+    }
+
+    #[test]
+    fn test_positive_and_negative_ttl() {
+        let cache = DecisionCache::new(Duration::from_secs(60), Duration::from_secs(5), 10);
+        let now = Instant::now();
+        cache.insert_at(&request("reader", "bucket01"), &true.into(), now);
+        cache.insert_at(&request("reader", "bucket02"), &false.into(), now);
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(
+            cache.get_at(&request("reader", "bucket01"), later),
+            Some(true.into())
+        );
+        assert_eq!(cache.get_at(&request("reader", "bucket02"), later), None);
+        assert_eq!(
+            cache.get_at(
+                &AccessRequest::new(Some("reader"), "bucket01", S3Operation::PutObject),
+                later
+            ),
+            None
+        );
+        assert_eq!((cache.hit_count(), cache.miss_count()), (1, 2));
+        assert_eq!(cache.size(), 1);
+    }
+
+    #[test]
+    fn test_object_key_and_client_address_are_part_of_the_key() {
+        let cache = DecisionCache::new(Duration::from_secs(60), Duration::from_secs(60), 10);
+        let now = Instant::now();
+        let public = AccessRequest {
+            key: Some("public/a.txt"),
+            client_addr: Some("10.0.0.1".parse().unwrap()),
+            ..request("reader", "bucket01")
+        };
+        cache.insert_at(&public, &true.into(), now);
+
+        let private = AccessRequest {
+            key: Some("private/a.txt"),
+            ..public.clone()
+        };
+        assert_eq!(cache.get_at(&private, now), None);
+        let other_client = AccessRequest {
+            client_addr: Some("10.0.0.2".parse().unwrap()),
+            ..public.clone()
+        };
+        assert_eq!(cache.get_at(&other_client, now), None);
+        assert_eq!(cache.get_at(&public, now), Some(true.into()));
+    }
+
+    #[test]
+    fn test_zero_ttl_disables_caching() {
+        let cache = DecisionCache::new(Duration::from_secs(60), Duration::ZERO, 10);
+        let now = Instant::now();
+        cache.insert_at(&request("reader", "bucket02"), &false.into(), now);
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn test_size_bound_evicts_oldest() {
+        let cache = DecisionCache::new(Duration::from_secs(60), Duration::from_secs(60), 2);
+        let now = Instant::now();
+        cache.insert_at(&request("a", "bucket01"), &true.into(), now);
+        cache.insert_at(&request("b", "bucket01"), &true.into(), now);
+        // refreshing an entry makes it the newest one
+        cache.insert_at(&request("a", "bucket01"), &true.into(), now);
+        cache.insert_at(&request("c", "bucket01"), &true.into(), now);
+
+        assert_eq!(cache.size(), 2);
+        assert_eq!(cache.get_at(&request("b", "bucket01"), now), None);
+        assert!(cache.get_at(&request("a", "bucket01"), now).is_some());
+        assert!(cache.get_at(&request("c", "bucket01"), now).is_some());
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let cache = DecisionCache::new(Duration::from_secs(60), Duration::from_secs(60), 10);
+        let now = Instant::now();
+        cache.insert_at(&request("a", "bucket01"), &true.into(), now);
+        cache.insert_at(&request("a", "bucket02"), &true.into(), now);
+        cache.insert_at(&request("b", "bucket01"), &true.into(), now);
+
+        assert_eq!(cache.invalidate_matching(Some("a"), Some("bucket02")), 1);
+        assert_eq!(cache.invalidate_matching(None, Some("bucket01")), 2);
+        assert_eq!(cache.size(), 0);
+
+        cache.insert_at(&request("a", "bucket01"), &true.into(), now);
+        assert_eq!(cache.invalidate_matching(None, None), 1);
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingValidator {
+        calls: AtomicU64,
+        /// decides on the access key, bucket and operation only
+        coarse: bool,
+    }
+
+    #[async_trait]
+    impl Validator for CountingValidator {
+        async fn validate(
+            &self,
+            _request: &AccessRequest<'_>,
+        ) -> Result<AuthDecision, CallbackError> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(true.into())
+        }
+
+        fn uses_key_and_client(&self) -> bool {
+            !self.coarse
+        }
+    }
+
+    #[tokio::test]
+    async fn test_caching_validator() {
+        let counting = Arc::new(CountingValidator::default());
+        let cache = DecisionCache::new(Duration::from_secs(60), Duration::from_secs(5), 10);
+        let validator = CachingValidator::new(cache.clone(), counting.clone());
+
+        for _ in 0..3 {
+            assert_eq!(
+                validator.validate(&request("reader", "bucket01")).await,
+                Ok(true.into())
+            );
+        }
+        assert_eq!(counting.calls.load(Ordering::Relaxed), 1);
+        assert_eq!((cache.hit_count(), cache.miss_count()), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn test_key_and_client_are_left_out_when_not_used() {
+        let objects = |key| AccessRequest {
+            key: Some(key),
+            client_addr: Some("10.0.0.1".parse().unwrap()),
+            ..request("reader", "bucket01")
+        };
+        for (coarse, calls) in [(false, 2), (true, 1)] {
+            let counting = Arc::new(CountingValidator {
+                coarse,
+                ..Default::default()
+            });
+            let cache = DecisionCache::new(Duration::from_secs(60), Duration::from_secs(5), 10);
+            let validator = CachingValidator::new(cache, counting.clone());
+            for key in ["a.txt", "b.txt", "a.txt"] {
+                assert_eq!(validator.validate(&objects(key)).await, Ok(true.into()));
+            }
+            assert_eq!(counting.calls.load(Ordering::Relaxed), calls);
+        }
+    }
+}
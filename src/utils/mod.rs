@@ -1 +1,8 @@
+pub mod bucket_resolver;
+pub mod decision_cache;
+pub mod operation;
+pub mod policy;
+pub mod python;
+pub mod s3_error;
+pub mod ttl_cache;
 pub mod validator;
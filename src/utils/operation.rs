@@ -0,0 +1,385 @@
+use std::collections::HashSet;
+use std::fmt;
+
+/// Query parameters of plain bucket listings
+const LIST_PARAMS: &[&str] = &[
+    "continuation-token",
+    "delimiter",
+    "encoding-type",
+    "fetch-owner",
+    "list-type",
+    "marker",
+    "max-keys",
+    "prefix",
+    "start-after",
+];
+
+/// Query parameters of plain object reads
+const GET_OBJECT_PARAMS: &[&str] = &[
+    "partNumber",
+    "response-cache-control",
+    "response-content-disposition",
+    "response-content-encoding",
+    "response-content-language",
+    "response-content-type",
+    "response-expires",
+    "versionId",
+];
+
+/// Query parameters of plain object deletes
+const DELETE_OBJECT_PARAMS: &[&str] = &["versionId"];
+
+/// Whether a query parameter can come with any operation: those of presigned URLs, and
+/// the `x-id` the AWS SDKs add to name the operation.
+fn is_common_param(name: &str) -> bool {
+    name == "x-id"
+        || name
+            .get(..6)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("x-amz-"))
+}
+
+/// The S3 API operation a request performs, named after the S3 API action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum S3Operation {
+    // bucket operations
+    ListObjects,
+    ListObjectsV2,
+    ListObjectVersions,
+    ListMultipartUploads,
+    HeadBucket,
+    CreateBucket,
+    DeleteBucket,
+    DeleteObjects,
+    GetBucketLocation,
+    GetBucketAcl,
+    PutBucketAcl,
+    GetBucketPolicy,
+    PutBucketPolicy,
+    DeleteBucketPolicy,
+    GetBucketCors,
+    PutBucketCors,
+    DeleteBucketCors,
+    GetBucketLifecycleConfiguration,
+    PutBucketLifecycleConfiguration,
+    DeleteBucketLifecycle,
+    GetBucketVersioning,
+    PutBucketVersioning,
+    GetBucketTagging,
+    PutBucketTagging,
+    DeleteBucketTagging,
+    // object operations
+    GetObject,
+    HeadObject,
+    PutObject,
+    CopyObject,
+    DeleteObject,
+    GetObjectAcl,
+    PutObjectAcl,
+    GetObjectTagging,
+    PutObjectTagging,
+    DeleteObjectTagging,
+    RestoreObject,
+    CreateMultipartUpload,
+    UploadPart,
+    UploadPartCopy,
+    CompleteMultipartUpload,
+    AbortMultipartUpload,
+    ListParts,
+    Unknown,
+}
+
+impl S3Operation {
+    /// Classifies a request from its method, the object key (empty for bucket requests),
+    /// the query string and whether it carries an `x-amz-copy-source` header. Requests for
+    /// subresources not listed here are `Unknown`, rather than taken for the plain
+    /// operation on the bucket or object.
+    pub fn classify(method: &str, key: &str, query: &str, copy_source: bool) -> Self {
+        let subresources: HashSet<&str> = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| pair.split_once('=').map_or(pair, |(name, _)| name))
+            .collect();
+        let has = |name: &str| subresources.contains(name);
+        // no query parameters besides `allowed` ones, i.e. not a subresource request
+        let plain = |allowed: &[&str]| {
+            subresources
+                .iter()
+                .all(|name| allowed.contains(name) || is_common_param(name))
+        };
+
+        use S3Operation::*;
+        if key.is_empty() {
+            match method {
+                "GET" if has("location") => GetBucketLocation,
+                "GET" if has("acl") => GetBucketAcl,
+                "GET" if has("policy") => GetBucketPolicy,
+                "GET" if has("cors") => GetBucketCors,
+                "GET" if has("lifecycle") => GetBucketLifecycleConfiguration,
+                "GET" if has("versioning") => GetBucketVersioning,
+                "GET" if has("tagging") => GetBucketTagging,
+                "GET" if has("uploads") => ListMultipartUploads,
+                "GET" if has("versions") => ListObjectVersions,
+                "GET" if !plain(LIST_PARAMS) => Unknown,
+                "GET" if query.split('&').any(|pair| pair == "list-type=2") => ListObjectsV2,
+                "GET" => ListObjects,
+                "HEAD" => HeadBucket,
+                "PUT" if has("acl") => PutBucketAcl,
+                "PUT" if has("policy") => PutBucketPolicy,
+                "PUT" if has("cors") => PutBucketCors,
+                "PUT" if has("lifecycle") => PutBucketLifecycleConfiguration,
+                "PUT" if has("versioning") => PutBucketVersioning,
+                "PUT" if has("tagging") => PutBucketTagging,
+                "PUT" if plain(&[]) => CreateBucket,
+                "DELETE" if has("policy") => DeleteBucketPolicy,
+                "DELETE" if has("cors") => DeleteBucketCors,
+                "DELETE" if has("lifecycle") => DeleteBucketLifecycle,
+                "DELETE" if has("tagging") => DeleteBucketTagging,
+                "DELETE" if plain(&[]) => DeleteBucket,
+                "POST" if has("delete") => DeleteObjects,
+                _ => Unknown,
+            }
+        } else {
+            match method {
+                "GET" if has("uploadId") => ListParts,
+                "GET" if has("acl") => GetObjectAcl,
+                "GET" if has("tagging") => GetObjectTagging,
+                "GET" if plain(GET_OBJECT_PARAMS) => GetObject,
+                "HEAD" => HeadObject,
+                "PUT" if has("uploadId") && copy_source => UploadPartCopy,
+                "PUT" if has("uploadId") => UploadPart,
+                "PUT" if has("acl") => PutObjectAcl,
+                "PUT" if has("tagging") => PutObjectTagging,
+                "PUT" if copy_source && plain(&[]) => CopyObject,
+                "PUT" if plain(&[]) => PutObject,
+                "POST" if has("uploads") => CreateMultipartUpload,
+                "POST" if has("uploadId") => CompleteMultipartUpload,
+                "POST" if has("restore") => RestoreObject,
+                "DELETE" if has("uploadId") => AbortMultipartUpload,
+                "DELETE" if has("tagging") => DeleteObjectTagging,
+                "DELETE" if plain(DELETE_OBJECT_PARAMS) => DeleteObject,
+                _ => Unknown,
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        use S3Operation::*;
+        match self {
+            ListObjects => "ListObjects",
+            ListObjectsV2 => "ListObjectsV2",
+            ListObjectVersions => "ListObjectVersions",
+            ListMultipartUploads => "ListMultipartUploads",
+            HeadBucket => "HeadBucket",
+            CreateBucket => "CreateBucket",
+            DeleteBucket => "DeleteBucket",
+            DeleteObjects => "DeleteObjects",
+            GetBucketLocation => "GetBucketLocation",
+            GetBucketAcl => "GetBucketAcl",
+            PutBucketAcl => "PutBucketAcl",
+            GetBucketPolicy => "GetBucketPolicy",
+            PutBucketPolicy => "PutBucketPolicy",
+            DeleteBucketPolicy => "DeleteBucketPolicy",
+            GetBucketCors => "GetBucketCors",
+            PutBucketCors => "PutBucketCors",
+            DeleteBucketCors => "DeleteBucketCors",
+            GetBucketLifecycleConfiguration => "GetBucketLifecycleConfiguration",
+            PutBucketLifecycleConfiguration => "PutBucketLifecycleConfiguration",
+            DeleteBucketLifecycle => "DeleteBucketLifecycle",
+            GetBucketVersioning => "GetBucketVersioning",
+            PutBucketVersioning => "PutBucketVersioning",
+            GetBucketTagging => "GetBucketTagging",
+            PutBucketTagging => "PutBucketTagging",
+            DeleteBucketTagging => "DeleteBucketTagging",
+            GetObject => "GetObject",
+            HeadObject => "HeadObject",
+            PutObject => "PutObject",
+            CopyObject => "CopyObject",
+            DeleteObject => "DeleteObject",
+            GetObjectAcl => "GetObjectAcl",
+            PutObjectAcl => "PutObjectAcl",
+            GetObjectTagging => "GetObjectTagging",
+            PutObjectTagging => "PutObjectTagging",
+            DeleteObjectTagging => "DeleteObjectTagging",
+            RestoreObject => "RestoreObject",
+            CreateMultipartUpload => "CreateMultipartUpload",
+            UploadPart => "UploadPart",
+            UploadPartCopy => "UploadPartCopy",
+            CompleteMultipartUpload => "CompleteMultipartUpload",
+            AbortMultipartUpload => "AbortMultipartUpload",
+            ListParts => "ListParts",
+            Unknown => "Unknown",
+        }
+    }
+}
+
+impl fmt::Display for S3Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use S3Operation::*;
+
+    #[test]
+    fn test_classify_bucket_operations() {
+        assert_eq!(S3Operation::classify("GET", "", "", false), ListObjects);
+        assert_eq!(
+            S3Operation::classify("GET", "", "list-type=2&prefix=logs%2F", false),
+            ListObjectsV2
+        );
+        assert_eq!(
+            S3Operation::classify("GET", "", "location", false),
+            GetBucketLocation
+        );
+        assert_eq!(
+            S3Operation::classify("GET", "", "uploads", false),
+            ListMultipartUploads
+        );
+        assert_eq!(S3Operation::classify("HEAD", "", "", false), HeadBucket);
+        assert_eq!(S3Operation::classify("PUT", "", "", false), CreateBucket);
+        assert_eq!(
+            S3Operation::classify("PUT", "", "versioning", false),
+            PutBucketVersioning
+        );
+        assert_eq!(S3Operation::classify("DELETE", "", "", false), DeleteBucket);
+        assert_eq!(
+            S3Operation::classify("POST", "", "delete", false),
+            DeleteObjects
+        );
+    }
+
+    #[test]
+    fn test_classify_object_operations() {
+        assert_eq!(
+            S3Operation::classify("GET", "a/b.txt", "", false),
+            GetObject
+        );
+        assert_eq!(
+            S3Operation::classify("GET", "a/b.txt", "X-Amz-Signature=abc", false),
+            GetObject
+        );
+        assert_eq!(
+            S3Operation::classify("HEAD", "a/b.txt", "", false),
+            HeadObject
+        );
+        assert_eq!(
+            S3Operation::classify("PUT", "a/b.txt", "", false),
+            PutObject
+        );
+        assert_eq!(
+            S3Operation::classify("PUT", "a/b.txt", "", true),
+            CopyObject
+        );
+        assert_eq!(
+            S3Operation::classify("DELETE", "a/b.txt", "", false),
+            DeleteObject
+        );
+        assert_eq!(
+            S3Operation::classify("PUT", "a/b.txt", "tagging", false),
+            PutObjectTagging
+        );
+    }
+
+    #[test]
+    fn test_classify_multipart_operations() {
+        assert_eq!(
+            S3Operation::classify("POST", "big.bin", "uploads", false),
+            CreateMultipartUpload
+        );
+        assert_eq!(
+            S3Operation::classify("PUT", "big.bin", "partNumber=1&uploadId=xyz", false),
+            UploadPart
+        );
+        assert_eq!(
+            S3Operation::classify("PUT", "big.bin", "partNumber=1&uploadId=xyz", true),
+            UploadPartCopy
+        );
+        assert_eq!(
+            S3Operation::classify("POST", "big.bin", "uploadId=xyz", false),
+            CompleteMultipartUpload
+        );
+        assert_eq!(
+            S3Operation::classify("DELETE", "big.bin", "uploadId=xyz", false),
+            AbortMultipartUpload
+        );
+        assert_eq!(
+            S3Operation::classify("GET", "big.bin", "uploadId=xyz", false),
+            ListParts
+        );
+    }
+
+    #[test]
+    fn test_classify_unknown() {
+        assert_eq!(S3Operation::classify("PATCH", "a", "", false), Unknown);
+        assert_eq!(S3Operation::classify("OPTIONS", "", "", false), Unknown);
+    }
+
+    #[test]
+    fn test_classify_unknown_subresources() {
+        for subresource in [
+            "encryption",
+            "website",
+            "replication",
+            "logging",
+            "publicAccessBlock",
+            "object-lock",
+            "notification",
+        ] {
+            for method in ["GET", "PUT", "DELETE"] {
+                assert_eq!(
+                    S3Operation::classify(method, "", subresource, false),
+                    Unknown,
+                    "{} ?{}",
+                    method,
+                    subresource
+                );
+            }
+        }
+        for subresource in ["retention", "legal-hold", "torrent", "attributes"] {
+            for method in ["GET", "PUT"] {
+                assert_eq!(
+                    S3Operation::classify(method, "a/b.txt", subresource, false),
+                    Unknown,
+                    "{} ?{}",
+                    method,
+                    subresource
+                );
+            }
+        }
+        assert_eq!(
+            S3Operation::classify("PUT", "a/b.txt", "retention", true),
+            Unknown
+        );
+
+        // pagination, response overrides and presigning are not subresources
+        assert_eq!(
+            S3Operation::classify("GET", "", "prefix=a&max-keys=10&marker=b", false),
+            ListObjects
+        );
+        assert_eq!(
+            S3Operation::classify(
+                "GET",
+                "a/b.txt",
+                "versionId=1&response-content-type=text%2Fplain&x-id=GetObject",
+                false
+            ),
+            GetObject
+        );
+        assert_eq!(
+            S3Operation::classify(
+                "PUT",
+                "a/b.txt",
+                "X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Signature=abc",
+                false
+            ),
+            PutObject
+        );
+        assert_eq!(
+            S3Operation::classify("DELETE", "a/b.txt", "versionId=1", false),
+            DeleteObject
+        );
+    }
+}
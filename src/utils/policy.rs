@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::Deserialize;
+
+use crate::utils::validator::AccessRequest;
+
+/// Resource prefix of S3 ARNs, optional in policy resources
+const S3_ARN_PREFIX: &str = "arn:aws:s3:::";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawPrincipal {
+    Aws {
+        #[serde(rename = "AWS")]
+        aws: OneOrMany,
+    },
+    Keys(OneOrMany),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RawStatement {
+    #[serde(default)]
+    sid: Option<String>,
+    effect: Effect,
+    principal: RawPrincipal,
+    action: OneOrMany,
+    resource: OneOrMany,
+    #[serde(default)]
+    condition: HashMap<String, HashMap<String, OneOrMany>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct RawPolicy {
+    statement: Vec<RawStatement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    IpAddress(Vec<IpNet>),
+    NotIpAddress(Vec<IpNet>),
+    DateGreaterThan(DateTime<Utc>),
+    DateLessThan(DateTime<Utc>),
+}
+
+impl Condition {
+    fn parse(operator: &str, key: &str, values: OneOrMany) -> Result<Self, String> {
+        let values = values.into_vec();
+        match (operator, key.to_ascii_lowercase().as_str()) {
+            ("IpAddress" | "NotIpAddress", "aws:sourceip") => {
+                let nets = values
+                    .iter()
+                    .map(|v| parse_net(v))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(if operator == "IpAddress" {
+                    Condition::IpAddress(nets)
+                } else {
+                    Condition::NotIpAddress(nets)
+                })
+            }
+            ("DateGreaterThan" | "DateLessThan", "aws:currenttime") => {
+                let [value] = values.as_slice() else {
+                    return Err(format!("{} takes a single date", operator));
+                };
+                let date = DateTime::parse_from_rfc3339(value)
+                    .map_err(|e| format!("invalid date {}: {}", value, e))?
+                    .with_timezone(&Utc);
+                Ok(if operator == "DateGreaterThan" {
+                    Condition::DateGreaterThan(date)
+                } else {
+                    Condition::DateLessThan(date)
+                })
+            }
+            _ => Err(format!("unsupported condition {} on {}", operator, key)),
+        }
+    }
+
+    fn matches(&self, client_addr: Option<IpAddr>, now: DateTime<Utc>) -> bool {
+        match self {
+            Condition::IpAddress(nets) => {
+                client_addr.is_some_and(|addr| nets.iter().any(|net| net.contains(&addr)))
+            }
+            Condition::NotIpAddress(nets) => {
+                client_addr.is_some_and(|addr| !nets.iter().any(|net| net.contains(&addr)))
+            }
+            Condition::DateGreaterThan(date) => now > *date,
+            Condition::DateLessThan(date) => now < *date,
+        }
+    }
+}
+
+/// Parses a CIDR block, a bare address being a single-host block.
+fn parse_net(value: &str) -> Result<IpNet, String> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid source ip {}", value))
+}
+
+/// Matches `value` against a pattern where `*` matches any run of characters and `?`
+/// a single one.
+fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, v));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                p += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    v = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Statement {
+    sid: Option<String>,
+    effect: Effect,
+    principals: Vec<String>,
+    actions: Vec<String>,
+    resources: Vec<String>,
+    conditions: Vec<Condition>,
+}
+
+impl Statement {
+    fn compile(raw: RawStatement) -> Result<Self, String> {
+        let principals = match raw.principal {
+            RawPrincipal::Aws { aws } => aws.into_vec(),
+            RawPrincipal::Keys(keys) => keys.into_vec(),
+        };
+        let actions = raw
+            .action
+            .into_vec()
+            .into_iter()
+            .map(|action| action.to_ascii_lowercase())
+            .collect();
+        let resources = raw
+            .resource
+            .into_vec()
+            .into_iter()
+            .map(|resource| {
+                resource
+                    .strip_prefix(S3_ARN_PREFIX)
+                    .map(str::to_string)
+                    .unwrap_or(resource)
+            })
+            .collect();
+        let mut conditions = Vec::new();
+        for (operator, entries) in raw.condition {
+            for (key, values) in entries {
+                conditions.push(Condition::parse(&operator, &key, values)?);
+            }
+        }
+        Ok(Statement {
+            sid: raw.sid,
+            effect: raw.effect,
+            principals,
+            actions,
+            resources,
+            conditions,
+        })
+    }
+
+    fn applies_to(&self, request: &AccessRequest, now: DateTime<Utc>) -> bool {
+        let principal = self
+            .principals
+            .iter()
+            .any(|p| p == "*" || Some(p.as_str()) == request.access_key);
+        let action = format!("s3:{}", request.operation).to_ascii_lowercase();
+        let resource = match request.key {
+            Some(key) => format!("{}/{}", request.bucket, key),
+            None => request.bucket.to_string(),
+        };
+
+        principal
+            && self.actions.iter().any(|a| glob_match(a, &action))
+            && self.resources.iter().any(|r| glob_match(r, &resource))
+            && self
+                .conditions
+                .iter()
+                .all(|c| c.matches(request.client_addr, now))
+    }
+}
+
+/// Outcome of evaluating a request against a `Policy`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyEvaluation {
+    /// An `Allow` statement matched and no `Deny` did
+    Allow,
+    /// A `Deny` statement matched, with its `Sid` if it has one
+    ExplicitDeny(Option<String>),
+    /// No statement matched
+    ImplicitDeny,
+}
+
+/// IAM-style policy document evaluated natively for every request. Statements match on
+/// principal (the request's access key, `*` for anyone), action (`s3:GetObject`, `s3:Get*`),
+/// resource (`bucket` for bucket operations, `bucket/prefix/*` for objects, with or without
+/// the `arn:aws:s3:::` prefix) and conditions on `aws:SourceIp` and `aws:CurrentTime`.
+/// An explicit deny always wins; without a matching allow the request is denied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    statements: Vec<Statement>,
+}
+
+impl Policy {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let raw: RawPolicy =
+            serde_json::from_str(json).map_err(|e| format!("invalid policy: {}", e))?;
+        let statements = raw
+            .statement
+            .into_iter()
+            .map(Statement::compile)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Policy { statements })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Self::from_json(&content).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn len(&self) -> usize {
+        self.statements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statements.is_empty()
+    }
+
+    pub fn evaluate(&self, request: &AccessRequest, now: DateTime<Utc>) -> PolicyEvaluation {
+        let mut allowed = false;
+        for statement in self
+            .statements
+            .iter()
+            .filter(|s| s.applies_to(request, now))
+        {
+            match statement.effect {
+                Effect::Deny => return PolicyEvaluation::ExplicitDeny(statement.sid.clone()),
+                Effect::Allow => allowed = true,
+            }
+        }
+        if allowed {
+            PolicyEvaluation::Allow
+        } else {
+            PolicyEvaluation::ImplicitDeny
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::operation::S3Operation;
+
+    const POLICY: &str = r#"{
+        "Version": "2012-10-17",
+        "Statement": [
+            {
+                "Sid": "ReadOnly",
+                "Effect": "Allow",
+                "Principal": ["reader"],
+                "Action": ["s3:Get*", "s3:HeadObject", "s3:ListObjects*"],
+                "Resource": ["arn:aws:s3:::bucket01", "arn:aws:s3:::bucket01/*"]
+            },
+            {
+                "Sid": "UploadsFromOffice",
+                "Effect": "Allow",
+                "Principal": {"AWS": "writer"},
+                "Action": "s3:*",
+                "Resource": "bucket01/uploads/*",
+                "Condition": {
+                    "IpAddress": {"aws:SourceIp": ["10.0.0.0/8", "192.168.1.10"]},
+                    "DateLessThan": {"aws:CurrentTime": "2030-01-01T00:00:00Z"}
+                }
+            },
+            {
+                "Sid": "NoSecrets",
+                "Effect": "Deny",
+                "Principal": "*",
+                "Action": "s3:*",
+                "Resource": "bucket01/secret/*"
+            }
+        ]
+    }"#;
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-06-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn request<'a>(
+        access_key: &'a str,
+        operation: S3Operation,
+        key: Option<&'a str>,
+        client_addr: &str,
+    ) -> AccessRequest<'a> {
+        AccessRequest {
+            access_key: Some(access_key),
+            bucket: "bucket01",
+            operation,
+            key,
+            client_addr: client_addr.parse().ok(),
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("s3:get*", "s3:getobject"));
+        assert!(glob_match(
+            "bucket01/*/report-??.csv",
+            "bucket01/a/b/report-01.csv"
+        ));
+        assert!(!glob_match("bucket01/*", "bucket01"));
+        assert!(!glob_match("s3:get*", "s3:putobject"));
+        assert!(!glob_match("bucket0?", "bucket010"));
+    }
+
+    #[test]
+    fn test_read_only_principal() {
+        let policy = Policy::from_json(POLICY).unwrap();
+        assert_eq!(policy.len(), 3);
+        let get = request("reader", S3Operation::GetObject, Some("a.txt"), "1.2.3.4");
+        let list = request("reader", S3Operation::ListObjectsV2, None, "1.2.3.4");
+        let put = request("reader", S3Operation::PutObject, Some("a.txt"), "1.2.3.4");
+        assert_eq!(policy.evaluate(&get, now()), PolicyEvaluation::Allow);
+        assert_eq!(policy.evaluate(&list, now()), PolicyEvaluation::Allow);
+        assert_eq!(policy.evaluate(&put, now()), PolicyEvaluation::ImplicitDeny);
+    }
+
+    #[test]
+    fn test_prefix_and_conditions() {
+        let policy = Policy::from_json(POLICY).unwrap();
+        let upload = request(
+            "writer",
+            S3Operation::PutObject,
+            Some("uploads/x.bin"),
+            "10.1.2.3",
+        );
+        assert_eq!(policy.evaluate(&upload, now()), PolicyEvaluation::Allow);
+
+        let single_host = request(
+            "writer",
+            S3Operation::PutObject,
+            Some("uploads/x.bin"),
+            "192.168.1.10",
+        );
+        assert_eq!(
+            policy.evaluate(&single_host, now()),
+            PolicyEvaluation::Allow
+        );
+
+        let outside = request(
+            "writer",
+            S3Operation::PutObject,
+            Some("uploads/x.bin"),
+            "8.8.8.8",
+        );
+        assert_eq!(
+            policy.evaluate(&outside, now()),
+            PolicyEvaluation::ImplicitDeny
+        );
+
+        let later = DateTime::parse_from_rfc3339("2031-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            policy.evaluate(&upload, later),
+            PolicyEvaluation::ImplicitDeny
+        );
+
+        let other_prefix = request("writer", S3Operation::PutObject, Some("x.bin"), "10.1.2.3");
+        assert_eq!(
+            policy.evaluate(&other_prefix, now()),
+            PolicyEvaluation::ImplicitDeny
+        );
+    }
+
+    #[test]
+    fn test_explicit_deny_wins() {
+        let policy = Policy::from_json(POLICY).unwrap();
+        let get = request(
+            "reader",
+            S3Operation::GetObject,
+            Some("secret/key.pem"),
+            "1.2.3.4",
+        );
+        assert_eq!(
+            policy.evaluate(&get, now()),
+            PolicyEvaluation::ExplicitDeny(Some("NoSecrets".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_anonymous_requests() {
+        let policy = Policy::from_json(POLICY).unwrap();
+        let anonymous = AccessRequest::new(None, "bucket01", S3Operation::GetObject);
+        assert_eq!(
+            policy.evaluate(&anonymous, now()),
+            PolicyEvaluation::ImplicitDeny
+        );
+    }
+
+    #[test]
+    fn test_invalid_policies() {
+        assert!(Policy::from_json("{}").is_err());
+        assert!(
+            Policy::from_json(
+                r#"{"Statement": [{"Effect": "Maybe", "Principal": "*", "Action": "*", "Resource": "*"}]}"#
+            )
+            .is_err()
+        );
+        assert!(
+            Policy::from_json(
+                r#"{"Statement": [{"Effect": "Allow", "Principal": "*", "Action": "*", "Resource": "*",
+                    "Condition": {"StringEquals": {"aws:username": "me"}}}]}"#
+            )
+            .is_err()
+        );
+        assert!(
+            Policy::from_json(
+                r#"{"Statement": [{"Effect": "Allow", "Principal": "*", "Action": "*", "Resource": "*",
+                    "Condition": {"IpAddress": {"aws:SourceIp": "not-an-ip"}}}]}"#
+            )
+            .is_err()
+        );
+    }
+}
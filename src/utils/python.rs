@@ -0,0 +1,393 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use pyo3::sync::GILOnceCell;
+use pyo3::types::{PyCFunction, PyDict, PyTuple};
+use tokio::sync::{Semaphore, oneshot};
+use tokio::time::{Instant, timeout_at};
+use tracing::{info, warn};
+
+/// The asyncio event loop coroutines returned by Python callbacks run on, started on its
+/// own thread the first time a callback returns one.
+static EVENT_LOOP: GILOnceCell<PyObject> = GILOnceCell::new();
+
+fn event_loop(py: Python<'_>) -> PyResult<&Bound<'_, PyAny>> {
+    EVENT_LOOP
+        .get_or_try_init(py, || {
+            let event_loop = py.import("asyncio")?.call_method0("new_event_loop")?;
+            let kwargs = PyDict::new(py);
+            kwargs.set_item("target", event_loop.getattr("run_forever")?)?;
+            kwargs.set_item("name", "object-storage-proxy-asyncio")?;
+            kwargs.set_item("daemon", true)?;
+            py.import("threading")?
+                .getattr("Thread")?
+                .call((), Some(&kwargs))?
+                .call_method0("start")?;
+            info!("Started asyncio event loop thread for Python callbacks");
+            Ok(event_loop.unbind())
+        })
+        .map(|event_loop| event_loop.bind(py))
+}
+
+fn is_awaitable(value: &Bound<'_, PyAny>) -> PyResult<bool> {
+    value
+        .py()
+        .import("inspect")?
+        .call_method1("isawaitable", (value,))?
+        .extract()
+}
+
+/// Schedules `coroutine` on the event loop thread and returns a `concurrent.futures.Future`
+/// for its result.
+fn schedule<'py>(coroutine: &Bound<'py, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+    let py = coroutine.py();
+    let asyncio = py.import("asyncio")?;
+    if !asyncio
+        .call_method1("iscoroutine", (coroutine,))?
+        .extract::<bool>()?
+    {
+        return Err(PyRuntimeError::new_err(
+            "async callbacks must return a coroutine, not another awaitable",
+        ));
+    }
+    asyncio.call_method1("run_coroutine_threadsafe", (coroutine, event_loop(py)?))
+}
+
+/// What a callback returned, or the result of its coroutine once the asyncio loop thread
+/// extracted it.
+enum Called<T> {
+    Returned(T),
+    Scheduled(oneshot::Receiver<Result<T, String>>, CancelOnDrop),
+}
+
+/// Calls `callback(*args)` and extracts what it returns. Coroutines (from `async def`
+/// callbacks) are run on the asyncio loop thread, which extracts their result as well, so
+/// the GIL is only ever taken by the blocking thread calling this and the loop thread.
+fn call_callback<A, T>(py: Python<'_>, callback: &PyObject, args: A) -> PyResult<Called<T>>
+where
+    A: for<'py> IntoPyObject<'py, Target = PyTuple>,
+    T: for<'py> FromPyObject<'py> + Send + 'static,
+{
+    let result = callback.call1(py, args)?.into_bound(py);
+    if !is_awaitable(&result)? {
+        return result.extract().map(Called::Returned);
+    }
+
+    let future = schedule(&result)?;
+    let pending = CancelOnDrop(Some(future.clone().unbind()));
+    let (sender, receiver) = oneshot::channel();
+    let sender = Mutex::new(Some(sender));
+    let on_done = PyCFunction::new_closure(
+        py,
+        None,
+        None,
+        move |args: &Bound<'_, PyTuple>, _kwargs| -> PyResult<()> {
+            let outcome = args
+                .get_item(0)?
+                .call_method0("result")
+                .and_then(|result| result.extract::<T>())
+                .map_err(|e| e.to_string());
+            if let Some(sender) = sender.lock().unwrap().take() {
+                let _ = sender.send(outcome);
+            }
+            Ok(())
+        },
+    )?;
+    future.call_method1("add_done_callback", (on_done,))?;
+    Ok(Called::Scheduled(receiver, pending))
+}
+
+/// Cancels a scheduled coroutine when whoever awaits it gives up, e.g. on a timeout. The
+/// cancellation is handed to the loop thread with `call_soon_threadsafe` from a blocking
+/// thread, as the GIL is not to be taken on a tokio worker.
+struct CancelOnDrop(Option<PyObject>);
+
+fn cancel(future: PyObject) {
+    Python::with_gil(|py| {
+        let cancelled = event_loop(py).and_then(|event_loop| {
+            event_loop.call_method1("call_soon_threadsafe", (future.getattr(py, "cancel")?,))
+        });
+        if let Err(e) = cancelled {
+            warn!("Failed to cancel a Python coroutine: {}", e);
+        }
+    });
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(future) = self.0.take() {
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn_blocking(move || cancel(future));
+                }
+                Err(_) => cancel(future),
+            }
+        }
+    }
+}
+
+/// Why a Python callback did not produce a result.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CallbackError {
+    /// Too many calls are already waiting for the pool
+    Overloaded,
+    TimedOut,
+    /// The callback raised or returned something unusable
+    Failed(String),
+}
+
+impl std::fmt::Display for CallbackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallbackError::Overloaded => write!(f, "too many pending Python callbacks"),
+            CallbackError::TimedOut => write!(f, "Python callback timed out"),
+            CallbackError::Failed(reason) => write!(f, "Python callback failed: {}", reason),
+        }
+    }
+}
+
+/// Counts a caller waiting for a pool slot for as long as it waits.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs Python callbacks off the request path with at most `workers` of them in flight.
+/// Up to `queue_limit` more calls wait for a slot, beyond that calls are shed. Each call,
+/// queueing included, gets `timeout` to produce its result.
+///
+/// Python cannot interrupt a plain function, so a sync callback that times out keeps its
+/// slot until it actually returns; at most `workers` of them tie up blocking threads, and
+/// a callback that hangs for good takes a worker away for good. Coroutines of `async def`
+/// callbacks are cancelled on timeout and give up their slot right away.
+#[derive(Debug)]
+pub struct CallbackPool {
+    permits: Arc<Semaphore>,
+    waiting: AtomicUsize,
+    queue_limit: usize,
+    timeout: Duration,
+}
+
+impl CallbackPool {
+    pub fn new(workers: usize, queue_limit: usize, timeout: Duration) -> Self {
+        CallbackPool {
+            permits: Arc::new(Semaphore::new(workers)),
+            waiting: AtomicUsize::new(0),
+            queue_limit,
+            timeout,
+        }
+    }
+
+    /// Calls `callback(*args)` on a blocking thread and extracts its result as `T`, after
+    /// resolving a returned coroutine on the asyncio loop thread. The GIL is never taken on
+    /// the calling tokio worker.
+    pub async fn call<A, T>(&self, callback: &Arc<PyObject>, args: A) -> Result<T, CallbackError>
+    where
+        A: for<'py> IntoPyObject<'py, Target = PyTuple> + Send + 'static,
+        T: for<'py> FromPyObject<'py> + Send + 'static,
+    {
+        let deadline = Instant::now() + self.timeout;
+
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if self.waiting.fetch_add(1, Ordering::Relaxed) >= self.queue_limit {
+                    self.waiting.fetch_sub(1, Ordering::Relaxed);
+                    warn!("Python callback queue is full, shedding the call");
+                    return Err(CallbackError::Overloaded);
+                }
+                let _waiting = Waiting(&self.waiting);
+                timeout_at(deadline, self.permits.clone().acquire_owned())
+                    .await
+                    .map_err(|_| CallbackError::TimedOut)?
+                    .map_err(|e| CallbackError::Failed(e.to_string()))?
+            }
+        };
+
+        let callback = callback.clone();
+        // the permit moves along so it is held until the callback actually returns,
+        // even when we stop waiting for it
+        let call = tokio::task::spawn_blocking(move || {
+            let called = Python::with_gil(|py| {
+                call_callback::<A, T>(py, &callback, args).map_err(|e| e.to_string())
+            });
+            (permit, called)
+        });
+        let (permit, called) = timeout_at(deadline, call)
+            .await
+            .map_err(|_| CallbackError::TimedOut)?
+            .map_err(|e| CallbackError::Failed(e.to_string()))?;
+
+        let resolved = match called.map_err(CallbackError::Failed)? {
+            Called::Returned(value) => Ok(value),
+            Called::Scheduled(receiver, mut pending) => {
+                let outcome = timeout_at(deadline, receiver)
+                    .await
+                    .map_err(|_| CallbackError::TimedOut)?
+                    .map_err(|_| {
+                        CallbackError::Failed("asyncio loop dropped the coroutine".to_string())
+                    })?;
+                pending.0 = None;
+                outcome.map_err(CallbackError::Failed)
+            }
+        };
+        drop(permit);
+        resolved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    /// The function `name` defined by `code`.
+    fn python_function(code: &CStr, name: &str) -> Arc<PyObject> {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            py.run(code, Some(&globals), None).unwrap();
+            Arc::new(globals.get_item(name).unwrap().unwrap().unbind())
+        })
+    }
+
+    fn sleeper() -> Arc<PyObject> {
+        python_function(
+            c"import time\ndef sleep(seconds):\n    time.sleep(seconds)\n    return seconds",
+            "sleep",
+        )
+    }
+
+    #[tokio::test]
+    async fn test_calls_beyond_the_queue_limit_are_shed() {
+        let pool = Arc::new(CallbackPool::new(1, 1, Duration::from_secs(5)));
+        let sleep = sleeper();
+
+        let running = tokio::spawn({
+            let (pool, sleep) = (pool.clone(), sleep.clone());
+            async move { pool.call::<_, f64>(&sleep, (0.3,)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let queued = tokio::spawn({
+            let (pool, sleep) = (pool.clone(), sleep.clone());
+            async move { pool.call::<_, f64>(&sleep, (0.0,)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            pool.call::<_, f64>(&sleep, (0.0,)).await.unwrap_err(),
+            CallbackError::Overloaded
+        );
+        assert!(running.await.unwrap().is_ok());
+        assert!(queued.await.unwrap().is_ok());
+        assert_eq!(pool.waiting.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_sync_callback_keeps_its_permit_until_it_returns() {
+        let pool = CallbackPool::new(1, 1, Duration::from_millis(100));
+        let sleep = sleeper();
+
+        assert_eq!(
+            pool.call::<_, f64>(&sleep, (0.4,)).await.unwrap_err(),
+            CallbackError::TimedOut
+        );
+        assert_eq!(pool.permits.available_permits(), 0);
+        // queued calls time out as well meanwhile
+        assert_eq!(
+            pool.call::<_, f64>(&sleep, (0.0,)).await.unwrap_err(),
+            CallbackError::TimedOut
+        );
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(pool.permits.available_permits(), 1);
+        assert!(pool.call::<_, f64>(&sleep, (0.0,)).await.is_ok());
+        assert_eq!(pool.permits.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_coroutine_releases_its_permit() {
+        let pool = CallbackPool::new(1, 1, Duration::from_millis(100));
+        let sleep = python_function(
+            c"import asyncio\ncancelled = []\nasync def sleep(seconds):\n    try:\n        await asyncio.sleep(seconds)\n    except asyncio.CancelledError:\n        cancelled.append(seconds)\n        raise",
+            "sleep",
+        );
+
+        assert_eq!(
+            pool.call::<_, PyObject>(&sleep, (5.0,)).await.unwrap_err(),
+            CallbackError::TimedOut
+        );
+        assert_eq!(pool.permits.available_permits(), 1);
+
+        // the coroutine is cancelled on the loop thread
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let cancelled = Python::with_gil(|py| {
+            sleep
+                .getattr(py, "__globals__")
+                .and_then(|globals| globals.bind(py).get_item("cancelled"))
+                .and_then(|cancelled| cancelled.extract::<Vec<f64>>())
+                .unwrap()
+        });
+        assert_eq!(cancelled, vec![5.0]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_callback_releases_its_permit() {
+        let pool = CallbackPool::new(1, 1, Duration::from_secs(5));
+        let fail = python_function(c"def fail():\n    raise ValueError('boom')", "fail");
+
+        let error = pool.call::<_, PyObject>(&fail, ()).await.unwrap_err();
+        assert!(matches!(&error, CallbackError::Failed(reason) if reason.contains("boom")));
+        assert_eq!(pool.permits.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_async_callbacks_are_awaited() {
+        let double = python_function(
+            c"import asyncio\nasync def double(n):\n    await asyncio.sleep(0.01)\n    return n * 2",
+            "double",
+        );
+        let pool = CallbackPool::new(2, 2, Duration::from_secs(5));
+
+        assert_eq!(pool.call::<_, i64>(&double, (21,)).await.unwrap(), 42);
+
+        // results that are no number fail the call
+        let error = pool.call::<_, i64>(&double, ("a",)).await.unwrap_err();
+        assert!(matches!(error, CallbackError::Failed(_)));
+
+        // plain values pass through untouched
+        let seven = python_function(c"def seven():\n    return 7", "seven");
+        assert_eq!(pool.call::<_, i64>(&seven, ()).await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_raising_coroutine_fails_the_call() {
+        let fail = python_function(
+            c"import asyncio\nasync def fail():\n    await asyncio.sleep(0)\n    raise ValueError('async boom')",
+            "fail",
+        );
+        let pool = CallbackPool::new(1, 1, Duration::from_secs(5));
+
+        let error = pool.call::<_, PyObject>(&fail, ()).await.unwrap_err();
+        assert!(matches!(&error, CallbackError::Failed(reason) if reason.contains("async boom")));
+        assert_eq!(pool.permits.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_awaitables_other_than_coroutines_are_rejected() {
+        let future = python_function(
+            c"import asyncio\ndef future():\n    return asyncio.Future(loop=asyncio.new_event_loop())",
+            "future",
+        );
+        let pool = CallbackPool::new(1, 1, Duration::from_secs(5));
+
+        let error = pool.call::<_, PyObject>(&future, ()).await.unwrap_err();
+        assert!(matches!(&error, CallbackError::Failed(reason) if reason.contains("coroutine")));
+    }
+}
@@ -0,0 +1,271 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use pingora::ErrorSource;
+use pingora::ErrorType::{ConnectionClosed, HTTPStatus, ReadError, WriteError};
+use pingora::http::ResponseHeader;
+use pingora::proxy::Session;
+
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a fresh id for a request, in the 16 hex digit format S3 uses.
+pub fn new_request_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    let count = REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016X}", nanos.rotate_left(16) ^ count)
+}
+
+fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Error codes upstreams answer with when they reject the credentials themselves, rather
+/// than what the credentials ask for.
+const CREDENTIAL_ERROR_CODES: &[&str] = &[
+    "InvalidAccessKeyId",
+    "ExpiredToken",
+    "InvalidToken",
+    "TokenRefreshRequired",
+];
+
+/// The `<Code>` of an S3 error response body.
+pub fn error_code(body: &[u8]) -> Option<&str> {
+    let body = std::str::from_utf8(body).ok()?;
+    let start = body.find("<Code>")? + "<Code>".len();
+    let end = start + body[start..].find("</Code>")?;
+    Some(body[start..end].trim())
+}
+
+/// The error code an upstream put in the headers of its error response, as S3 website
+/// endpoints and MinIO (for bodiless responses) do.
+pub fn header_error_code(response: &ResponseHeader) -> Option<&str> {
+    ["x-amz-error-code", "x-minio-error-code"]
+        .into_iter()
+        .find_map(|name| response.headers.get(name))
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Whether an upstream error says the credentials were rejected: any 401, which is what
+/// IAM token errors come back as, or a 400 or 403 with a credential error code. Other 403s,
+/// e.g. `AccessDenied` by a bucket policy, are about the request and not the credentials.
+pub fn rejects_credentials(status: u16, code: Option<&str>) -> bool {
+    match status {
+        401 => true,
+        400 | 403 => code.is_some_and(|code| CREDENTIAL_ERROR_CODES.contains(&code)),
+        _ => false,
+    }
+}
+
+/// An error answered to the client the way S3 does: an HTTP status with an
+/// `<Error><Code/><Message/><RequestId/></Error>` body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct S3Error {
+    pub status: u16,
+    pub code: String,
+    pub message: String,
+}
+
+impl S3Error {
+    pub fn new(status: u16, code: impl Into<String>, message: impl Into<String>) -> Self {
+        S3Error {
+            status,
+            code: code.into(),
+            message: message.into(),
+        }
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(400, "InvalidRequest", message)
+    }
+
+    pub fn access_denied() -> Self {
+        Self::new(403, "AccessDenied", "Access Denied")
+    }
+
+    pub fn no_such_bucket() -> Self {
+        Self::new(404, "NoSuchBucket", "The specified bucket does not exist.")
+    }
+
+    pub fn invalid_access_key_id() -> Self {
+        Self::new(
+            403,
+            "InvalidAccessKeyId",
+            "The AWS Access Key Id you provided does not exist in our records.",
+        )
+    }
+
+    pub fn signature_does_not_match() -> Self {
+        Self::new(
+            403,
+            "SignatureDoesNotMatch",
+            "The request signature we calculated does not match the signature you provided.",
+        )
+    }
+
+    pub fn content_sha256_mismatch() -> Self {
+        Self::new(
+            400,
+            "XAmzContentSHA256Mismatch",
+            "The provided 'x-amz-content-sha256' header does not match what was computed.",
+        )
+    }
+
+    pub fn service_unavailable() -> Self {
+        Self::new(
+            503,
+            "ServiceUnavailable",
+            "The service is unavailable. Please retry your request.",
+        )
+    }
+
+    pub fn slow_down() -> Self {
+        Self::new(503, "SlowDown", "Please reduce your request rate.")
+    }
+
+    pub fn internal_error() -> Self {
+        Self::new(
+            500,
+            "InternalError",
+            "We encountered an internal error. Please try again.",
+        )
+    }
+
+    /// Maps an error raised while proxying to what the client should see, or `None` when
+    /// the client connection is gone and there is nobody to answer.
+    pub fn from_proxy_error(e: &pingora::Error) -> Option<Self> {
+        if let HTTPStatus(status) = e.etype() {
+            return Some(match status {
+                403 => Self::access_denied(),
+                503 => Self::service_unavailable(),
+                500.. => Self::new(*status, "InternalError", "Internal Error"),
+                _ => Self::new(*status, "InvalidRequest", "Invalid Request."),
+            });
+        }
+        match e.esource() {
+            ErrorSource::Upstream => Some(Self::service_unavailable()),
+            ErrorSource::Downstream => match e.etype() {
+                WriteError | ReadError | ConnectionClosed => None,
+                _ => Some(Self::invalid_request("Invalid Request.")),
+            },
+            ErrorSource::Internal | ErrorSource::Unset => Some(Self::internal_error()),
+        }
+    }
+
+    pub fn to_xml(&self, request_id: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code><Message>{}</Message><RequestId>{}</RequestId></Error>",
+            escape_xml(&self.code),
+            escape_xml(&self.message),
+            escape_xml(request_id)
+        )
+    }
+
+    /// Writes the error as the complete response to the client.
+    pub async fn respond(&self, session: &mut Session, request_id: &str) -> pingora::Result<()> {
+        let body = self.to_xml(request_id);
+        let mut header = ResponseHeader::build(self.status, None)?;
+        header.insert_header("Content-Type", "application/xml")?;
+        header.insert_header("Content-Length", body.len().to_string())?;
+        header.insert_header("x-amz-request-id", request_id)?;
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        session
+            .write_response_body(Some(Bytes::from(body)), true)
+            .await
+    }
+}
+
+impl std::fmt::Display for S3Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}: {}", self.status, self.code, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_xml() {
+        assert_eq!(
+            S3Error::no_such_bucket().to_xml("4442587FB7D0A2F9"),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>NoSuchBucket</Code><Message>The specified bucket does not exist.</Message><RequestId>4442587FB7D0A2F9</RequestId></Error>"
+        );
+        assert!(
+            S3Error::new(403, "AccessDenied", "no <access> for \"you\" & me")
+                .to_xml("1")
+                .contains("<Message>no &lt;access&gt; for &quot;you&quot; &amp; me</Message>")
+        );
+    }
+
+    #[test]
+    fn test_new_request_id() {
+        let first = new_request_id();
+        let second = new_request_id();
+        assert_eq!(first.len(), 16);
+        assert!(first.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_from_proxy_error() {
+        let upstream =
+            pingora::Error::explain(pingora::ErrorType::ConnectTimedout, "timeout").into_up();
+        assert_eq!(
+            S3Error::from_proxy_error(&upstream),
+            Some(S3Error::service_unavailable())
+        );
+
+        let closed = pingora::Error::explain(ConnectionClosed, "gone").into_down();
+        assert_eq!(S3Error::from_proxy_error(&closed), None);
+
+        let internal = pingora::Error::new_str("boom");
+        assert_eq!(
+            S3Error::from_proxy_error(&internal),
+            Some(S3Error::internal_error())
+        );
+
+        let forbidden = pingora::Error::new(HTTPStatus(403));
+        assert_eq!(
+            S3Error::from_proxy_error(&forbidden),
+            Some(S3Error::access_denied())
+        );
+    }
+
+    #[test]
+    fn test_rejects_credentials() {
+        let body = b"<?xml version=\"1.0\"?>\n<Error><Code>ExpiredToken</Code><Message>expired</Message></Error>";
+        assert_eq!(error_code(body), Some("ExpiredToken"));
+        assert_eq!(error_code(b"<Error></Error>"), None);
+
+        let mut response = ResponseHeader::build(403, None).unwrap();
+        assert_eq!(header_error_code(&response), None);
+        response
+            .insert_header("x-minio-error-code", "InvalidAccessKeyId")
+            .unwrap();
+        assert_eq!(header_error_code(&response), Some("InvalidAccessKeyId"));
+
+        assert!(rejects_credentials(401, None));
+        assert!(rejects_credentials(403, Some("InvalidToken")));
+        assert!(rejects_credentials(400, Some("ExpiredToken")));
+        assert!(!rejects_credentials(403, Some("AccessDenied")));
+        assert!(!rejects_credentials(403, None));
+        assert!(!rejects_credentials(404, Some("InvalidToken")));
+    }
+}
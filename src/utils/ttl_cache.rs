@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use tokio::sync::OnceCell;
+
+/// Most keys a `TtlCache` remembers; absent values are cached as well, so this keeps
+/// clients probing random names from growing the cache without bound.
+pub(crate) const MAX_CACHED_KEYS: usize = 10_000;
+
+#[derive(Debug)]
+struct Entry<V> {
+    value: Option<V>,
+    expires: Instant,
+}
+
+/// A lookup in progress, shared by everyone asking for the same key meanwhile.
+type Flight<V, E> = Arc<OnceCell<Result<Option<V>, E>>>;
+
+/// Remembers looked up values by key: those found for `ttl`, and `None` for `negative_ttl`;
+/// a zero TTL disables caching them. Failed lookups are not cached. Concurrent lookups of
+/// the same key share a single call, so a burst of requests for a new key costs one.
+#[derive(Debug)]
+pub struct TtlCache<V, E> {
+    ttl: Duration,
+    negative_ttl: Duration,
+    entries: RwLock<HashMap<String, Entry<V>>>,
+    in_flight: Mutex<HashMap<String, Flight<V, E>>>,
+}
+
+impl<V: Clone, E: Clone> TtlCache<V, E> {
+    pub fn new(ttl: Duration, negative_ttl: Duration) -> Self {
+        TtlCache {
+            ttl,
+            negative_ttl,
+            entries: RwLock::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn cached(&self, key: &str, now: Instant) -> Option<Option<V>> {
+        self.entries
+            .read()
+            .unwrap()
+            .get(key)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| entry.value.clone())
+    }
+
+    pub(crate) fn remember(&self, key: &str, value: &Option<V>, now: Instant) {
+        let ttl = match value {
+            Some(_) => self.ttl,
+            None => self.negative_ttl,
+        };
+        if ttl.is_zero() {
+            return;
+        }
+
+        let mut entries = self.entries.write().unwrap();
+        if entries.len() >= MAX_CACHED_KEYS {
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= MAX_CACHED_KEYS {
+                entries.clear();
+            }
+        }
+        entries.insert(
+            key.to_string(),
+            Entry {
+                value: value.clone(),
+                expires: now + ttl,
+            },
+        );
+    }
+
+    /// The cached value for `key`, or what `lookup` returns, which only one of the callers
+    /// asking for `key` at the same time runs.
+    pub async fn get_or_lookup<F, Fut>(&self, key: &str, lookup: F) -> Result<Option<V>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<V>, E>>,
+    {
+        if let Some(value) = self.cached(key, Instant::now()) {
+            return Ok(value);
+        }
+
+        let flight = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            in_flight.entry(key.to_string()).or_default().clone()
+        };
+        let result = flight
+            .get_or_init(|| async {
+                // a flight that just landed may have filled it
+                if let Some(value) = self.cached(key, Instant::now()) {
+                    return Ok(value);
+                }
+                let result = lookup().await;
+                if let Ok(value) = &result {
+                    self.remember(key, value, Instant::now());
+                }
+                result
+            })
+            .await
+            .clone();
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(key)
+            .is_some_and(|current| Arc::ptr_eq(current, &flight))
+        {
+            in_flight.remove(key);
+        }
+        result
+    }
+
+    pub fn invalidate(&self, key: &str) {
+        self.entries.write().unwrap().remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type Cache = TtlCache<String, String>;
+
+    #[test]
+    fn test_entries_expire() {
+        let cache = Cache::new(Duration::from_secs(300), Duration::from_secs(30));
+        let now = Instant::now();
+        cache.remember("known", &Some("value".to_string()), now);
+        cache.remember("unknown", &None, now);
+        assert_eq!(cache.cached("unknown", now), Some(None));
+        assert_eq!(cache.cached("other", now), None);
+
+        let later = now + Duration::from_secs(31);
+        assert_eq!(
+            cache.cached("known", later),
+            Some(Some("value".to_string()))
+        );
+        assert_eq!(cache.cached("unknown", later), None);
+        assert_eq!(cache.cached("known", now + Duration::from_secs(301)), None);
+    }
+
+    #[test]
+    fn test_zero_ttl_disables_caching() {
+        let cache = Cache::new(Duration::from_secs(300), Duration::ZERO);
+        let now = Instant::now();
+        cache.remember("unknown", &None, now);
+        assert_eq!(cache.cached("unknown", now), None);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lookups_share_one_call() {
+        let cache = Arc::new(Cache::new(
+            Duration::from_secs(300),
+            Duration::from_secs(30),
+        ));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let lookup = |calls: Arc<AtomicUsize>| async move {
+            calls.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(Some("value".to_string()))
+        };
+
+        let lookups: Vec<_> = (0..10)
+            .map(|_| {
+                let (cache, calls) = (cache.clone(), calls.clone());
+                tokio::spawn(async move { cache.get_or_lookup("key", || lookup(calls)).await })
+            })
+            .collect();
+        for result in futures::future::join_all(lookups).await {
+            assert_eq!(result.unwrap(), Ok(Some("value".to_string())));
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+
+        cache.invalidate("key");
+        cache
+            .get_or_lookup("key", || lookup(calls.clone()))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failed_lookups_are_not_cached() {
+        let cache = Cache::new(Duration::from_secs(300), Duration::from_secs(30));
+        let failed = cache
+            .get_or_lookup("key", || async { Err("boom".to_string()) })
+            .await;
+        assert_eq!(failed, Err("boom".to_string()));
+        assert_eq!(cache.cached("key", Instant::now()), None);
+    }
+}
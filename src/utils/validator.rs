@@ -1,42 +1,1072 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use pingora::http::RequestHeader;
+use pyo3::prelude::*;
 use pyo3::{PyObject, Python};
-use tracing::{error, info};
+use tracing::{debug, error, info};
+
+use crate::credentials::signer::{self, ChunkSigner};
+use crate::parsers::credentials::{
+    AuthorizationHeader, PresignedParams, SigV4Auth, is_presigned, parse_authorization,
+    parse_presigned_params,
+};
+use crate::utils::operation::S3Operation;
+use crate::utils::python::{CallbackError, CallbackPool};
+use crate::utils::s3_error::S3Error;
+
+/// Maximum allowed difference between `x-amz-date` and the proxy clock, as enforced by S3.
+const MAX_CLOCK_SKEW_SECS: i64 = 15 * 60;
+
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    Malformed(String),
+    InvalidAccessKeyId(String),
+    RequestTimeTooSkewed,
+    SignatureDoesNotMatch,
+    Expired,
+    UnsupportedSignatureVersion(String),
+}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureError::Malformed(reason) => write!(f, "malformed request: {}", reason),
+            SignatureError::InvalidAccessKeyId(key) => write!(f, "unknown access key: {}", key),
+            SignatureError::RequestTimeTooSkewed => write!(f, "request time too skewed"),
+            SignatureError::SignatureDoesNotMatch => write!(f, "signature does not match"),
+            SignatureError::Expired => write!(f, "presigned request has expired"),
+            SignatureError::UnsupportedSignatureVersion(key) => {
+                write!(f, "SigV2 request from access key {}", key)
+            }
+        }
+    }
+}
+
+impl SignatureError {
+    /// The S3 error to answer the client with
+    pub fn s3_error(&self) -> S3Error {
+        match self {
+            SignatureError::Malformed(_) => S3Error::new(
+                400,
+                "AuthorizationHeaderMalformed",
+                "The authorization header is malformed.",
+            ),
+            SignatureError::InvalidAccessKeyId(_) => S3Error::invalid_access_key_id(),
+            SignatureError::RequestTimeTooSkewed => S3Error::new(
+                403,
+                "RequestTimeTooSkewed",
+                "The difference between the request time and the current time is too large.",
+            ),
+            SignatureError::SignatureDoesNotMatch => S3Error::signature_does_not_match(),
+            SignatureError::Expired => S3Error::new(403, "AccessDenied", "Request has expired"),
+            SignatureError::UnsupportedSignatureVersion(_) => S3Error::new(
+                400,
+                "InvalidRequest",
+                "The authorization mechanism you have provided is not supported. Please use AWS4-HMAC-SHA256.",
+            ),
+        }
+    }
+}
+
+fn header_str<'a>(req: &'a RequestHeader, name: &str) -> Option<&'a str> {
+    req.headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Seven days, the longest validity S3 accepts for a presigned URL
+pub const MAX_PRESIGNED_EXPIRES_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Access key id used in presigned URLs issued by the proxy itself
+pub const PRESIGN_ACCESS_KEY: &str = "object-storage-proxy";
+
+/// Whether the query string carries a presigned URL issued by the proxy
+pub fn is_proxy_presigned(query: &str) -> bool {
+    is_presigned(query)
+        && parse_presigned_params(query).is_ok_and(|params| params.access_key == PRESIGN_ACCESS_KEY)
+}
+
+fn parse_amz_date(amz_date: &str) -> Result<NaiveDateTime, SignatureError> {
+    NaiveDateTime::parse_from_str(amz_date, "%Y%m%dT%H%M%SZ")
+        .map_err(|_| SignatureError::Malformed("invalid x-amz-date".into()))
+}
+
+/// Recomputes the SigV4 signature of an inbound request and compares it with the one
+/// in the `Authorization` header or, for presigned URLs, the query string, using the
+/// secret registered for the access key. Returns the access key on success.
+pub fn verify_signature(
+    req: &RequestHeader,
+    keystore: &HashMap<String, String>,
+) -> Result<String, SignatureError> {
+    let query = req.uri.query().unwrap_or("");
+    if header_str(req, "authorization").is_none() && is_presigned(query) {
+        return verify_presigned_signature(req, keystore);
+    }
+
+    let header = header_str(req, "authorization").unwrap_or("");
+    let auth = parse_sigv4(header)?;
+
+    let secret_key = keystore
+        .get(auth.access_key)
+        .ok_or_else(|| SignatureError::InvalidAccessKeyId(auth.access_key.to_string()))?;
+
+    let amz_date = header_str(req, "x-amz-date")
+        .ok_or_else(|| SignatureError::Malformed("missing x-amz-date header".into()))?;
+    let skew = (Utc::now().naive_utc() - parse_amz_date(amz_date)?)
+        .num_seconds()
+        .abs();
+    if skew > MAX_CLOCK_SKEW_SECS {
+        return Err(SignatureError::RequestTimeTooSkewed);
+    }
+
+    let payload_hash = header_str(req, "x-amz-content-sha256").unwrap_or(signer::UNSIGNED_PAYLOAD);
+    check_signature(
+        req,
+        secret_key,
+        amz_date,
+        &auth.credential_scope(),
+        &auth.signed_headers.join(";"),
+        payload_hash,
+        auth.signature,
+    )?;
 
-use crate::parsers::credentials::parse_token_from_header;
+    Ok(auth.access_key.to_string())
+}
 
-pub fn validate_request(
-    header: &str,
-    bucket: &str,
-    py: Python,
-    callback: &PyObject,
-) -> Result<bool, String> {
+fn parse_sigv4(header: &str) -> Result<SigV4Auth<'_>, SignatureError> {
+    match parse_authorization(header) {
+        Ok(AuthorizationHeader::SigV4(auth)) => Ok(auth),
+        Ok(AuthorizationHeader::SigV2 { access_key, .. }) => Err(
+            SignatureError::UnsupportedSignatureVersion(access_key.to_string()),
+        ),
+        Err(e) => Err(SignatureError::Malformed(e.to_string())),
+    }
+}
+
+/// Rejects malformed and SigV2 `Authorization` headers when signatures aren't verified.
+/// An absent header is left to the validator.
+pub fn check_authorization_header(header: &str) -> Result<(), SignatureError> {
     if header.is_empty() {
-        return Err("Header is empty".to_string());
+        return Ok(());
+    }
+    parse_sigv4(header).map(|_| ())
+}
+
+/// Builds the signer used to verify the chunk signatures of a
+/// `STREAMING-AWS4-HMAC-SHA256-PAYLOAD` upload, seeded with the header signature.
+/// Expects the request headers to have passed `verify_signature` already.
+pub fn streaming_chunk_signer(
+    req: &RequestHeader,
+    keystore: &HashMap<String, String>,
+) -> Result<ChunkSigner, SignatureError> {
+    let header = header_str(req, "authorization").unwrap_or("");
+    let auth = parse_sigv4(header)?;
+    let secret_key = keystore
+        .get(auth.access_key)
+        .ok_or_else(|| SignatureError::InvalidAccessKeyId(auth.access_key.to_string()))?;
+    let amz_date = header_str(req, "x-amz-date")
+        .ok_or_else(|| SignatureError::Malformed("missing x-amz-date header".into()))?;
+
+    Ok(ChunkSigner::new(
+        signer::signing_key(secret_key, auth.date, auth.region, auth.service),
+        amz_date,
+        &auth.credential_scope(),
+        auth.signature,
+    ))
+}
+
+/// Rejects presigned URLs that are past `X-Amz-Date` + `X-Amz-Expires`, dated in the
+/// future or valid for longer than S3 allows.
+pub fn check_presigned_expiry(params: &PresignedParams) -> Result<(), SignatureError> {
+    if params.expires > MAX_PRESIGNED_EXPIRES_SECS {
+        return Err(SignatureError::Malformed(
+            "X-Amz-Expires must be less than a week".into(),
+        ));
+    }
+    let signed_at = parse_amz_date(&params.amz_date)?;
+    let now = Utc::now().naive_utc();
+    if (signed_at - now).num_seconds() > MAX_CLOCK_SKEW_SECS {
+        return Err(SignatureError::RequestTimeTooSkewed);
+    }
+    if (now - signed_at).num_seconds() > params.expires as i64 {
+        return Err(SignatureError::Expired);
+    }
+    Ok(())
+}
+
+fn verify_presigned_signature(
+    req: &RequestHeader,
+    keystore: &HashMap<String, String>,
+) -> Result<String, SignatureError> {
+    let params =
+        parse_presigned_params(req.uri.query().unwrap_or("")).map_err(SignatureError::Malformed)?;
+
+    let secret_key = keystore
+        .get(&params.access_key)
+        .ok_or_else(|| SignatureError::InvalidAccessKeyId(params.access_key.clone()))?;
+
+    check_presigned_expiry(&params)?;
+    check_signature(
+        req,
+        secret_key,
+        &params.amz_date,
+        &params.credential_scope,
+        &params.signed_headers,
+        signer::UNSIGNED_PAYLOAD,
+        &params.signature,
+    )?;
+
+    Ok(params.access_key)
+}
+
+fn check_signature(
+    req: &RequestHeader,
+    secret_key: &str,
+    amz_date: &str,
+    scope: &str,
+    signed_headers: &str,
+    payload_hash: &str,
+    provided_signature: &str,
+) -> Result<(), SignatureError> {
+    let scope_parts: Vec<&str> = scope.split('/').collect();
+    let [date, region, service, "aws4_request"] = scope_parts[..] else {
+        return Err(SignatureError::Malformed("invalid credential scope".into()));
+    };
+
+    let signed_header_names: Vec<&str> = signed_headers.split(';').collect();
+    let canonical_headers = signer::canonical_headers(&req.headers, &signed_header_names)
+        .map_err(|name| SignatureError::Malformed(format!("signed header {} missing", name)))?;
+
+    let canonical_request = signer::canonical_request(
+        req.method.as_str(),
+        &signer::canonical_uri(req.uri.path()),
+        &signer::canonical_query_string(req.uri.query().unwrap_or(""), &["X-Amz-Signature"]),
+        &canonical_headers,
+        signed_headers,
+        payload_hash,
+    );
+    debug!("Canonical request:\n{}", canonical_request);
+
+    let string_to_sign = signer::string_to_sign(amz_date, scope, &canonical_request);
+    let key = signer::signing_key(secret_key, date, region, service);
+    let expected_signature = signer::signature(&key, &string_to_sign);
+
+    if !signer::signatures_match(&expected_signature, provided_signature) {
+        return Err(SignatureError::SignatureDoesNotMatch);
+    }
+    Ok(())
+}
+
+/// Extracts the access key from the `Authorization` header, or from the query string of
+/// a presigned URL. Returns `None` for anonymous requests.
+pub fn extract_access_key(header: &str, query: &str) -> Result<Option<String>, String> {
+    if !header.is_empty() {
+        match parse_authorization(header) {
+            Ok(AuthorizationHeader::SigV4(auth)) => Ok(Some(auth.access_key.to_string())),
+            Ok(AuthorizationHeader::SigV2 { access_key, .. }) => Err(format!(
+                "SigV2 is not supported (access key {})",
+                access_key
+            )),
+            Err(e) => Err(format!("Invalid header format: {}", e)),
+        }
+    } else if is_presigned(query) {
+        let params = parse_presigned_params(query)?;
+        check_presigned_expiry(&params).map_err(|e| e.to_string())?;
+        Ok(Some(params.access_key))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Outcome of a validator: besides allow/deny, a denial can carry the status and S3 error
+/// code to answer with, and an allowed request extra upstream headers or another bucket.
+#[pyclass(name = "AuthDecision")]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct AuthDecision {
+    #[pyo3(get, set)]
+    pub allow: bool,
+
+    /// HTTP status of the denial, 403 by default
+    #[pyo3(get)]
+    pub status: Option<u16>,
+
+    /// S3 error code of the denial, `AccessDenied` by default
+    #[pyo3(get, set)]
+    pub code: Option<String>,
+
+    /// Human-readable reason, only logged
+    #[pyo3(get, set)]
+    pub reason: Option<String>,
+
+    /// Headers added to the upstream request
+    #[pyo3(get, set)]
+    pub headers: HashMap<String, String>,
+
+    /// Bucket to forward the request to instead of the requested one
+    #[pyo3(get, set)]
+    pub bucket: Option<String>,
+}
+
+#[pymethods]
+impl AuthDecision {
+    #[new]
+    #[pyo3(signature = (allow, status=None, code=None, reason=None, headers=None, bucket=None))]
+    pub fn new(
+        allow: bool,
+        status: Option<u16>,
+        code: Option<String>,
+        reason: Option<String>,
+        headers: Option<HashMap<String, String>>,
+        bucket: Option<String>,
+    ) -> PyResult<Self> {
+        check_denial_status(status)?;
+        Ok(AuthDecision {
+            allow,
+            status,
+            code,
+            reason,
+            headers: headers.unwrap_or_default(),
+            bucket,
+        })
+    }
+
+    #[setter]
+    pub fn set_status(&mut self, status: Option<u16>) -> PyResult<()> {
+        check_denial_status(status)?;
+        self.status = status;
+        Ok(())
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+fn is_error_status(status: u16) -> bool {
+    (400..600).contains(&status)
+}
+
+fn check_denial_status(status: Option<u16>) -> PyResult<()> {
+    if status.is_some_and(|status| !is_error_status(status)) {
+        return Err(pyo3::exceptions::PyValueError::new_err(
+            "status must be an HTTP error status (4xx or 5xx)",
+        ));
+    }
+    Ok(())
+}
+
+impl From<bool> for AuthDecision {
+    fn from(allow: bool) -> Self {
+        AuthDecision {
+            allow,
+            ..Default::default()
+        }
+    }
+}
+
+impl AuthDecision {
+    /// The S3 error to deny the request with, 403 `AccessDenied` unless overridden; a
+    /// denial is never answered with anything but an error status.
+    pub fn denial(&self) -> S3Error {
+        S3Error::new(
+            self.status
+                .filter(|status| is_error_status(*status))
+                .unwrap_or(403),
+            self.code.as_deref().unwrap_or("AccessDenied"),
+            "Access Denied",
+        )
+    }
+}
+
+/// What a validator callback returns: a bool or an `AuthDecision`.
+struct CallbackDecision(AuthDecision);
+
+impl<'py> FromPyObject<'py> for CallbackDecision {
+    fn extract_bound(ob: &Bound<'py, PyAny>) -> PyResult<Self> {
+        if let Ok(is_authorized) = ob.extract::<bool>() {
+            return Ok(CallbackDecision(AuthDecision::from(is_authorized)));
+        }
+        ob.extract::<AuthDecision>()
+            .map(CallbackDecision)
+            .map_err(|_| {
+                pyo3::exceptions::PyTypeError::new_err(
+                    "Failed to extract bool or AuthDecision from Python callback",
+                )
+            })
+    }
+}
+
+/// Hands the request to the Python validator callback on `pool`. The callback returns
+/// either a bool or an `AuthDecision`, or a coroutine resolving to one for `async def`
+/// callbacks. Callbacks taking only `(access_key, bucket)` are called without the
+/// operation, object key and client address.
+pub async fn validate_request(
+    token: &str,
+    request: &AccessRequest<'_>,
+    callback: &Arc<PyObject>,
+    extended: bool,
+    pool: &CallbackPool,
+) -> Result<AuthDecision, CallbackError> {
+    let result = if extended {
+        let args = (
+            token.to_string(),
+            request.bucket.to_string(),
+            request.operation.as_str(),
+            request.key.map(str::to_string),
+            request.client_addr.map(|addr| addr.to_string()),
+        );
+        pool.call::<_, CallbackDecision>(callback, args).await
+    } else {
+        let args = (token.to_string(), request.bucket.to_string());
+        pool.call::<_, CallbackDecision>(callback, args).await
+    };
+
+    let CallbackDecision(decision) =
+        result.inspect_err(|e| error!("Python validator failed: {}", e))?;
+    info!("Callback returned: {:?}", decision);
+    Ok(decision)
+}
+
+/// What a request wants to do, as seen by a `Validator`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessRequest<'a> {
+    /// `None` for anonymous requests
+    pub access_key: Option<&'a str>,
+    pub bucket: &'a str,
+    pub operation: S3Operation,
+    /// Decoded object key, `None` for bucket operations
+    pub key: Option<&'a str>,
+    pub client_addr: Option<IpAddr>,
+}
+
+impl<'a> AccessRequest<'a> {
+    /// A request without an object key or client address.
+    pub fn new(access_key: Option<&'a str>, bucket: &'a str, operation: S3Operation) -> Self {
+        AccessRequest {
+            access_key,
+            bucket,
+            operation,
+            key: None,
+            client_addr: None,
+        }
+    }
+}
+
+/// Decides whether a request may go through. Only validators backed by a Python callback
+/// can fail.
+#[async_trait]
+pub trait Validator: Send + Sync + std::fmt::Debug {
+    async fn validate(&self, request: &AccessRequest<'_>) -> Result<AuthDecision, CallbackError>;
+
+    /// Whether decisions may depend on the object key and client address of a request, and
+    /// not only on its access key, bucket and operation.
+    fn uses_key_and_client(&self) -> bool {
+        true
+    }
+}
+
+/// Lets every request through, which is what the proxy does without a validator.
+#[derive(Debug, Default)]
+pub struct AllowAllValidator;
+
+#[async_trait]
+impl Validator for AllowAllValidator {
+    async fn validate(&self, _request: &AccessRequest<'_>) -> Result<AuthDecision, CallbackError> {
+        Ok(AuthDecision::from(true))
+    }
+
+    fn uses_key_and_client(&self) -> bool {
+        false
+    }
+}
+
+/// Bucket grants per access key; a `*` bucket grants access to every bucket.
+pub type AccessTable = HashMap<String, Vec<String>>;
+
+fn table_allows(table: &AccessTable, access_key: Option<&str>, bucket: &str) -> bool {
+    access_key
+        .and_then(|key| table.get(key))
+        .is_some_and(|buckets| buckets.iter().any(|b| b == "*" || b == bucket))
+}
+
+/// Validates against a fixed access key -> buckets table.
+#[derive(Debug)]
+pub struct StaticValidator {
+    table: AccessTable,
+}
+
+impl StaticValidator {
+    pub fn new(table: AccessTable) -> Self {
+        StaticValidator { table }
+    }
+}
+
+#[async_trait]
+impl Validator for StaticValidator {
+    async fn validate(&self, request: &AccessRequest<'_>) -> Result<AuthDecision, CallbackError> {
+        Ok(table_allows(&self.table, request.access_key, request.bucket).into())
+    }
+
+    fn uses_key_and_client(&self) -> bool {
+        false
+    }
+}
+
+/// Minimum time between two checks of the access table file for changes
+const FILE_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+struct FileTableState {
+    table: AccessTable,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+/// Validates against an access key -> buckets table read from a JSON file,
+/// e.g. `{"AKID": ["bucket1", "bucket2"]}`. The file is reloaded when it changes.
+#[derive(Debug)]
+pub struct FileValidator {
+    path: PathBuf,
+    state: RwLock<FileTableState>,
+}
+
+impl FileValidator {
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        let (table, modified) = Self::load(&path)?;
+        info!(
+            "Loaded access table with {} access keys from {}",
+            table.len(),
+            path.display()
+        );
+        Ok(FileValidator {
+            path,
+            state: RwLock::new(FileTableState {
+                table,
+                modified,
+                checked: Instant::now(),
+            }),
+        })
+    }
+
+    fn load(path: &Path) -> Result<(AccessTable, Option<SystemTime>), String> {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Ok((Self::parse(path, &content)?, modified))
     }
 
-    if !header.starts_with("AWS4-HMAC-SHA256 Credential=") {
-        return Err("Invalid header format".to_string());
+    fn parse(path: &Path, content: &str) -> Result<AccessTable, String> {
+        serde_json::from_str(content)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
     }
 
-    let token = parse_token_from_header(header).map_err(|_| "Failed to parse token")?;
-    let (_, token) = token;
+    /// Checks the file for changes at most every `FILE_RELOAD_INTERVAL`, on the request
+    /// that finds the interval over; the others keep using the current table meanwhile.
+    async fn reload_if_changed(&self) {
+        let last_modified = {
+            let mut state = self.state.write().unwrap();
+            if state.checked.elapsed() < FILE_RELOAD_INTERVAL {
+                return;
+            }
+            state.checked = Instant::now();
+            state.modified
+        };
 
-    match callback.call1(py, (token, bucket)) {
-        Ok(result) => {
-            let is_authorized = result
-                .extract::<bool>(py)
-                .map_err(|_| "Failed to extract boolean from Python callback")?;
-            info!("Callback returned: {:?}", is_authorized);
-            return Ok(is_authorized);
+        let modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|m| m.modified())
+            .ok();
+        if modified == last_modified {
+            return;
         }
-        Err(err) => {
-            error!("Python callback raised an exception: {:?}", err);
+        let table = match tokio::fs::read_to_string(&self.path).await {
+            Ok(content) => Self::parse(&self.path, &content),
+            Err(e) => Err(format!("Failed to read {}: {}", self.path.display(), e)),
+        };
+        match table {
+            Ok(table) => {
+                info!("Reloaded access table from {}", self.path.display());
+                let mut state = self.state.write().unwrap();
+                state.table = table;
+                state.modified = modified;
+            }
+            // keep serving the last good table
+            Err(e) => error!("{}", e),
+        }
+    }
+}
 
-            // Option 1: Return the error to Python (so Python sees the exception)
-            // return Err(err);
+#[async_trait]
+impl Validator for FileValidator {
+    async fn validate(&self, request: &AccessRequest<'_>) -> Result<AuthDecision, CallbackError> {
+        self.reload_if_changed().await;
+        let state = self.state.read().unwrap();
+        Ok(table_allows(&state.table, request.access_key, request.bucket).into())
+    }
+
+    fn uses_key_and_client(&self) -> bool {
+        false
+    }
+}
 
-            // Option 2: Convert it into a custom Python exception or a new error message
-            return Err("Failed to call callback due to an inner Python exception".to_string());
+/// Calls the Python `validator` callback with the access key, bucket, operation, object key
+/// and client address, or only the first two for callbacks that don't take more.
+#[derive(Debug)]
+pub struct PythonValidator {
+    callback: Arc<PyObject>,
+    extended: bool,
+    pool: Arc<CallbackPool>,
+}
+
+impl PythonValidator {
+    pub fn new(py: Python, callback: PyObject, pool: Arc<CallbackPool>) -> Self {
+        let extended = accepts_positional_args(py, &callback, 5);
+        if !extended {
+            info!("Python validator takes (access_key, bucket) only");
+        }
+        PythonValidator {
+            callback: Arc::new(callback),
+            extended,
+            pool,
         }
     }
+}
+
+/// Whether `callback` can be called with `count` positional arguments.
+fn accepts_positional_args(py: Python, callback: &PyObject, count: usize) -> bool {
+    let check = || -> PyResult<bool> {
+        let signature = py
+            .import("inspect")?
+            .call_method1("signature", (callback,))?;
+        let args = pyo3::types::PyTuple::new(py, (0..count).map(|_| py.None()))?;
+        Ok(signature.call_method1("bind", args).is_ok())
+    };
+    // builtins may not expose a signature, assume they follow the full convention
+    check().unwrap_or(true)
+}
+
+#[async_trait]
+impl Validator for PythonValidator {
+    async fn validate(&self, request: &AccessRequest<'_>) -> Result<AuthDecision, CallbackError> {
+        let Some(access_key) = request.access_key else {
+            return Ok(AuthDecision {
+                allow: false,
+                reason: Some("anonymous request".to_string()),
+                ..Default::default()
+            });
+        };
+        validate_request(
+            access_key,
+            request,
+            &self.callback,
+            self.extended,
+            &self.pool,
+        )
+        .await
+    }
+
+    /// Callbacks taking `(access_key, bucket)` only never see the object key or client.
+    fn uses_key_and_client(&self) -> bool {
+        self.extended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACCESS_KEY: &str = "MYLOCAL123";
+    const SECRET_KEY: &str = "my-very-secret-key";
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
 
+    fn keystore() -> HashMap<String, String> {
+        HashMap::from([(ACCESS_KEY.to_string(), SECRET_KEY.to_string())])
+    }
+
+    fn signed_request(secret_key: &str) -> RequestHeader {
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let date = &amz_date[..8];
+        let mut req =
+            RequestHeader::build("GET", b"/bucket01/some/key.txt?list-type=2", None).unwrap();
+        req.insert_header("host", "localhost:6190").unwrap();
+        req.insert_header("x-amz-date", &amz_date).unwrap();
+        req.insert_header("x-amz-content-sha256", EMPTY_SHA256)
+            .unwrap();
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let scope = format!("{}/eu-west-3/s3/aws4_request", date);
+        let canonical_request = signer::canonical_request(
+            "GET",
+            "/bucket01/some/key.txt",
+            "list-type=2",
+            &signer::canonical_headers(
+                &req.headers,
+                &["host", "x-amz-content-sha256", "x-amz-date"],
+            )
+            .unwrap(),
+            signed_headers,
+            EMPTY_SHA256,
+        );
+        let key = signer::signing_key(secret_key, date, "eu-west-3", "s3");
+        let signature = signer::signature(
+            &key,
+            &signer::string_to_sign(&amz_date, &scope, &canonical_request),
+        );
+        req.insert_header(
+            "authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                ACCESS_KEY, scope, signed_headers, signature
+            ),
+        )
+        .unwrap();
+        req
+    }
+
+    #[test]
+    fn test_verify_signature_success() {
+        let req = signed_request(SECRET_KEY);
+        assert_eq!(
+            verify_signature(&req, &keystore()),
+            Ok(ACCESS_KEY.to_string())
+        );
+    }
+
+    #[test]
+    fn test_verify_signature_wrong_secret() {
+        let req = signed_request("some-other-secret");
+        assert_eq!(
+            verify_signature(&req, &keystore()),
+            Err(SignatureError::SignatureDoesNotMatch)
+        );
+    }
+
+    #[test]
+    fn test_verify_signature_tampered_header() {
+        let mut req = signed_request(SECRET_KEY);
+        req.insert_header("host", "evil.example.com").unwrap();
+        assert_eq!(
+            verify_signature(&req, &keystore()),
+            Err(SignatureError::SignatureDoesNotMatch)
+        );
+    }
+
+    #[test]
+    fn test_verify_signature_unknown_access_key() {
+        let req = signed_request(SECRET_KEY);
+        assert_eq!(
+            verify_signature(&req, &HashMap::new()),
+            Err(SignatureError::InvalidAccessKeyId(ACCESS_KEY.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_verify_signature_skewed_date() {
+        let mut req = signed_request(SECRET_KEY);
+        req.insert_header("x-amz-date", "20200101T000000Z").unwrap();
+        assert_eq!(
+            verify_signature(&req, &keystore()),
+            Err(SignatureError::RequestTimeTooSkewed)
+        );
+    }
+
+    fn presigned_request(secret_key: &str, amz_date: &str, expires: u64) -> RequestHeader {
+        let date = &amz_date[..8];
+        let credential = format!("{}%2F{}%2Feu-west-3%2Fs3%2Faws4_request", ACCESS_KEY, date);
+        let query = format!(
+            "X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+            credential, amz_date, expires
+        );
+        let mut headers = http::HeaderMap::new();
+        headers.insert("host", "localhost:6190".parse().unwrap());
+        let canonical_request = signer::canonical_request(
+            "GET",
+            "/bucket01/some/key.txt",
+            &signer::canonical_query_string(&query, &[]),
+            &signer::canonical_headers(&headers, &["host"]).unwrap(),
+            "host",
+            signer::UNSIGNED_PAYLOAD,
+        );
+        let scope = format!("{}/eu-west-3/s3/aws4_request", date);
+        let key = signer::signing_key(secret_key, date, "eu-west-3", "s3");
+        let signature = signer::signature(
+            &key,
+            &signer::string_to_sign(amz_date, &scope, &canonical_request),
+        );
+
+        let uri = format!(
+            "/bucket01/some/key.txt?{}&X-Amz-Signature={}",
+            query, signature
+        );
+        let mut req = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+        req.insert_header("host", "localhost:6190").unwrap();
+        req
+    }
+
+    #[test]
+    fn test_verify_presigned_signature_success() {
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let req = presigned_request(SECRET_KEY, &amz_date, 3600);
+        assert_eq!(
+            verify_signature(&req, &keystore()),
+            Ok(ACCESS_KEY.to_string())
+        );
+    }
+
+    #[test]
+    fn test_verify_presigned_signature_wrong_secret() {
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let req = presigned_request("some-other-secret", &amz_date, 3600);
+        assert_eq!(
+            verify_signature(&req, &keystore()),
+            Err(SignatureError::SignatureDoesNotMatch)
+        );
+    }
+
+    #[test]
+    fn test_verify_presigned_signature_expired() {
+        let amz_date = (Utc::now() - chrono::Duration::hours(2))
+            .format("%Y%m%dT%H%M%SZ")
+            .to_string();
+        let req = presigned_request(SECRET_KEY, &amz_date, 3600);
+        assert_eq!(
+            verify_signature(&req, &keystore()),
+            Err(SignatureError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_check_presigned_expiry_too_long() {
+        let params = PresignedParams {
+            access_key: ACCESS_KEY.to_string(),
+            credential_scope: "20250417/eu-west-3/s3/aws4_request".to_string(),
+            amz_date: Utc::now().format("%Y%m%dT%H%M%SZ").to_string(),
+            expires: MAX_PRESIGNED_EXPIRES_SECS + 1,
+            signed_headers: "host".to_string(),
+            signature: "abc".to_string(),
+        };
+        assert!(matches!(
+            check_presigned_expiry(&params),
+            Err(SignatureError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_is_proxy_presigned() {
+        let url = signer::presign_url(
+            "http://localhost:6190",
+            "/bucket01/key",
+            "GET",
+            PRESIGN_ACCESS_KEY,
+            "proxy-secret",
+            "us-east-1",
+            60,
+            Utc::now(),
+        )
+        .unwrap();
+        let (_, query) = url.split_once('?').unwrap();
+        assert!(is_proxy_presigned(query));
+
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let req = presigned_request(SECRET_KEY, &amz_date, 3600);
+        assert!(!is_proxy_presigned(req.uri.query().unwrap()));
+    }
+
+    #[test]
+    fn test_verify_signature_sigv2() {
+        let mut req = RequestHeader::build("GET", b"/bucket01/key", None).unwrap();
+        req.insert_header(
+            "authorization",
+            "AWS MYLOCAL123:frJIUN8DYpKDtOLCwo//yllqDzg=",
+        )
+        .unwrap();
+        assert_eq!(
+            verify_signature(&req, &keystore()),
+            Err(SignatureError::UnsupportedSignatureVersion(
+                ACCESS_KEY.to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_check_authorization_header() {
+        assert_eq!(check_authorization_header(""), Ok(()));
+        assert!(matches!(
+            check_authorization_header("AWS4-HMAC-SHA256 Credential=AK"),
+            Err(SignatureError::Malformed(_))
+        ));
+        assert!(matches!(
+            check_authorization_header("AWS AK:c2lnbmF0dXJl"),
+            Err(SignatureError::UnsupportedSignatureVersion(_))
+        ));
+    }
+
+    #[test]
+    fn test_extract_access_key() {
+        let req = signed_request(SECRET_KEY);
+        let header = header_str(&req, "authorization").unwrap();
+        assert_eq!(
+            extract_access_key(header, ""),
+            Ok(Some(ACCESS_KEY.to_string()))
+        );
+        assert_eq!(extract_access_key("", "list-type=2"), Ok(None));
+        assert!(extract_access_key("AWS AK:c2lnbmF0dXJl", "").is_err());
+
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let req = presigned_request(SECRET_KEY, &amz_date, 3600);
+        assert_eq!(
+            extract_access_key("", req.uri.query().unwrap()),
+            Ok(Some(ACCESS_KEY.to_string()))
+        );
+    }
+
+    fn access_table() -> AccessTable {
+        HashMap::from([
+            ("reader".to_string(), vec!["bucket01".to_string()]),
+            ("admin".to_string(), vec!["*".to_string()]),
+        ])
+    }
+
+    fn get_object<'a>(access_key: Option<&'a str>, bucket: &'a str) -> AccessRequest<'a> {
+        AccessRequest::new(access_key, bucket, S3Operation::GetObject)
+    }
+
+    #[test]
+    fn test_auth_decision_denial() {
+        assert_eq!(AuthDecision::from(false).denial(), S3Error::access_denied());
+
+        let decision = AuthDecision {
+            allow: false,
+            status: Some(451),
+            code: Some("UnavailableForLegalReasons".to_string()),
+            reason: Some("embargoed".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            decision.denial(),
+            S3Error::new(451, "UnavailableForLegalReasons", "Access Denied")
+        );
+    }
+
+    #[test]
+    fn test_auth_decision_status_must_be_an_error() {
+        let mut decision = AuthDecision::from(false);
+        assert!(decision.set_status(Some(200)).is_err());
+        assert!(decision.set_status(Some(302)).is_err());
+        assert_eq!(decision.status, None);
+        decision.set_status(Some(429)).unwrap();
+        assert_eq!(decision.denial().status, 429);
+
+        // set from Rust, or however else it got there
+        decision.status = Some(200);
+        assert_eq!(decision.denial().status, 403);
+    }
+
+    #[tokio::test]
+    async fn test_allow_all_validator() {
+        assert_eq!(
+            AllowAllValidator
+                .validate(&get_object(None, "bucket01"))
+                .await,
+            Ok(true.into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_static_validator() {
+        let validator = StaticValidator::new(access_table());
+        assert_eq!(
+            validator
+                .validate(&get_object(Some("reader"), "bucket01"))
+                .await,
+            Ok(true.into())
+        );
+        assert_eq!(
+            validator
+                .validate(&get_object(Some("reader"), "bucket02"))
+                .await,
+            Ok(false.into())
+        );
+        assert_eq!(
+            validator
+                .validate(&get_object(Some("admin"), "bucket02"))
+                .await,
+            Ok(true.into())
+        );
+        assert_eq!(
+            validator
+                .validate(&get_object(Some("unknown"), "bucket01"))
+                .await,
+            Ok(false.into())
+        );
+        assert_eq!(
+            validator.validate(&get_object(None, "bucket01")).await,
+            Ok(false.into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_validator() {
+        let path =
+            std::env::temp_dir().join(format!("osp-access-table-{}.json", std::process::id()));
+        std::fs::write(&path, serde_json::to_string(&access_table()).unwrap()).unwrap();
+
+        let validator = FileValidator::new(&path).unwrap();
+        assert_eq!(
+            validator
+                .validate(&get_object(Some("reader"), "bucket01"))
+                .await,
+            Ok(true.into())
+        );
+        assert_eq!(
+            validator
+                .validate(&get_object(Some("reader"), "bucket02"))
+                .await,
+            Ok(false.into())
+        );
+
+        // picked up on the first request after the reload interval
+        let mut table = access_table();
+        table.insert("reader".to_string(), vec!["bucket02".to_string()]);
+        std::fs::write(&path, serde_json::to_string(&table).unwrap()).unwrap();
+        let force_check = || {
+            let mut state = validator.state.write().unwrap();
+            state.checked -= FILE_RELOAD_INTERVAL;
+            state.modified = None;
+        };
+        force_check();
+        assert_eq!(
+            validator
+                .validate(&get_object(Some("reader"), "bucket02"))
+                .await,
+            Ok(true.into())
+        );
+
+        // a broken file keeps the last good table
+        std::fs::write(&path, "not json").unwrap();
+        force_check();
+        assert_eq!(
+            validator
+                .validate(&get_object(Some("reader"), "bucket02"))
+                .await,
+            Ok(true.into())
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(FileValidator::new(&path).is_err());
+    }
+
+    #[test]
+    fn test_verify_signature_missing_header() {
+        let req = RequestHeader::build("GET", b"/bucket01", None).unwrap();
+        assert!(matches!(
+            verify_signature(&req, &keystore()),
+            Err(SignatureError::Malformed(_))
+        ));
+    }
 }
@@ -15,8 +15,8 @@ def docreds(bucket):
     print(f"Fetching credentials for {bucket}...")
     return apikey
 
-def do_validation(token: str, bucket: str) -> bool:
-    print(f"PYTHON: Validating headers: {token} for {bucket}...")
+def do_validation(token: str, bucket: str, operation: str, key: str | None, client_address: str | None) -> bool:
+    print(f"PYTHON: Validating {operation} on {bucket}/{key or ''} for {token} from {client_address}...")
     return random.choice([True, False])
 
 
@@ -34,11 +34,16 @@ def main():
     ]
 
 
+    hmac_keystore = {
+        "MYLOCAL123": "MYLOCALSECRET123",
+    }
+
     ra = ProxyServerConfig(
         bucket_creds_fetcher=docreds,
         validator=do_validation,
         cos_map=cos_mapping,
-        port=6190
+        port=6190,
+        hmac_keystore=hmac_keystore,
     )
 
     start_server(ra)